@@ -0,0 +1,69 @@
+//! Compares the performance of the representations of values, by running
+//! programs that mostly do arithmetic and programs that mostly call functions.
+//!
+//! Run with `cargo bench`, and with `cargo bench --features nan-boxing`
+//! to measure the NaN-boxed representation.
+
+use std::io::sink;
+use std::time::{Duration, Instant};
+use rlox::VM;
+
+/// Number of times each program is run, the fastest run is reported
+const RUNS: usize = 5;
+
+const ARITHMETIC: &str = "
+fn arithmetic() {
+    let total = 0;
+    for (let i = 0; i < 2000000; i = i + 1) {
+        let x = i * 1.5 - i / 4;
+        total = total + x % 7 - (x + 1) * 0.5;
+    }
+    return total;
+}
+print(arithmetic());
+";
+
+const CALLS: &str = "
+fn fib(n) {
+    if (n < 2) return n;
+    return fib(n - 1) + fib(n - 2);
+}
+print(fib(27));
+";
+
+const METHODS: &str = "
+class Counter {
+    init() { this.count = 0; }
+    add(n) { this.count = this.count + n; return this; }
+}
+
+fn count() {
+    let counter = Counter();
+    for (let i = 0; i < 500000; i = i + 1) {
+        counter.add(i).add(1);
+    }
+    return counter.count;
+}
+print(count());
+";
+
+fn main() {
+    let representation = if cfg!(feature = "nan-boxing") { "nan-boxing" } else { "enum" };
+    println!("Values represented with: {representation}");
+
+    for (name, source) in [("arithmetic", ARITHMETIC), ("calls", CALLS), ("methods", METHODS)] {
+        let best = (0..RUNS).map(|_| run(source)).min().unwrap();
+        println!("{name:<12} {:>8.1} ms", best.as_secs_f64() * 1000.0);
+    }
+}
+
+fn run(source: &str) -> Duration {
+    let mut vm = VM::new();
+    vm.set_output(Box::new(sink()));
+
+    let start = Instant::now();
+    if let Err(err) = vm.interpret(source) {
+        panic!("Benchmark failed: {err}");
+    }
+    start.elapsed()
+}
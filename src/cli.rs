@@ -0,0 +1,118 @@
+// Parsing of the command-line arguments of the rlox binary
+
+use rlox::TraceFormat;
+
+pub const USAGE: &str = "\
+Usage: rlox [options] [<file.lox> | -] [args...]
+       rlox run [options] <file.lox | -> [args...]
+       rlox repl [options]
+       rlox check [options] <file.lox | ->
+       rlox dis [options] <file.lox | ->
+
+Commands:
+  run      Run a script, the default when a script is given
+  repl     Start an interactive prompt, the default with no script
+  check    Compile a script and report its errors, without running it
+  dis      Print the bytecode of a script
+
+Scripts are read from the standard input when the file is '-'.
+Arguments after the script are available to it in the `args` list.
+
+Options:
+  -e, --eval <code>  Use the given code as the script
+  --trace            Print every instruction to stderr as it runs
+  --trace-json       Like --trace, but printing a JSON object per instruction
+  -h, --help         Show this message
+  -V, --version      Show the version of rlox";
+
+pub struct Cli {
+    pub command: Command,
+    pub trace: Option<TraceFormat>,
+}
+
+pub enum Command {
+    Run { script: Script, args: Vec<String> },
+    Repl,
+    Check(Script),
+    Disassemble(Script),
+    Help,
+    Version,
+}
+
+/// Where the source code of a script comes from
+pub enum Script {
+    File(String),
+    Stdin,
+    Eval(String),
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Subcommand {
+    Run,
+    Repl,
+    Check,
+    Disassemble,
+}
+
+/** Parses the arguments given to the binary, without the name of the binary itself */
+pub fn parse_args(args: impl IntoIterator<Item = String>) -> Result<Cli, String> {
+    let mut args = args.into_iter().peekable();
+
+    let subcommand = match args.peek().map(String::as_str) {
+        Some("run") => Some(Subcommand::Run),
+        Some("repl") => Some(Subcommand::Repl),
+        Some("check") => Some(Subcommand::Check),
+        Some("dis") => Some(Subcommand::Disassemble),
+        _ => None,
+    };
+    if subcommand.is_some() {
+        args.next();
+    }
+
+    let mut trace = None;
+    let mut script = None;
+
+    // Options go before the script, everything after it belongs to the script
+    while script.is_none() {
+        let Some(arg) = args.next() else { break };
+
+        match arg.as_str() {
+            "-h" | "--help" => return Ok(Cli { command: Command::Help, trace }),
+            "-V" | "--version" => return Ok(Cli { command: Command::Version, trace }),
+            "--trace" => trace = Some(TraceFormat::Text),
+            "--trace-json" => trace = Some(TraceFormat::JsonLines),
+            "-e" | "--eval" => match args.next() {
+                Some(code) => script = Some(Script::Eval(code)),
+                None => return Err(format!("Missing code after '{arg}'.")),
+            },
+            "--" => match args.next() {
+                Some(path) => script = Some(script_from_path(path)),
+                None => break,
+            },
+            "-" => script = Some(Script::Stdin),
+            _ if arg.starts_with('-') => return Err(format!("Unknown option '{arg}'.")),
+            _ => script = Some(script_from_path(arg)),
+        }
+    }
+
+    let args: Vec<String> = args.collect();
+    let takes_args = matches!(subcommand, None | Some(Subcommand::Run));
+    if !takes_args && !args.is_empty() {
+        return Err(format!("Unexpected argument '{}'.", args[0]));
+    }
+
+    let command = match (subcommand, script) {
+        (None, None) | (Some(Subcommand::Repl), None) => Command::Repl,
+        (Some(Subcommand::Repl), Some(_)) => return Err("The REPL doesn't take a script.".to_owned()),
+        (None | Some(Subcommand::Run), Some(script)) => Command::Run { script, args },
+        (Some(Subcommand::Check), Some(script)) => Command::Check(script),
+        (Some(Subcommand::Disassemble), Some(script)) => Command::Disassemble(script),
+        (Some(_), None) => return Err("Missing the script to use.".to_owned()),
+    };
+
+    Ok(Cli { command, trace })
+}
+
+fn script_from_path(path: String) -> Script {
+    if path == "-" { Script::Stdin } else { Script::File(path) }
+}
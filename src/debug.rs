@@ -1,67 +1,248 @@
 // Implementations of Debug and Display that are useful to
 // know what's going on inside the VM as it runs.
 
-use std::fmt::{Debug, Formatter, Result, Display};
-use std::io::Write;
+use std::fmt::{self, Debug, Formatter, Result, Display};
+use std::io::{self, sink, Write};
+use std::rc::Rc;
 use crate::runtime::{OpCodes, Chunk};
-use crate::values::{LoxValue, LoxObject};
+use crate::values::{LoxValue, LoxObject, LoxFunction, LoxList};
 
 type Fmtr<'a, 'b> = &'a mut Formatter<'b>;
 
 impl Debug for Chunk {
     fn fmt(&self, f: Fmtr) -> Result {
-        let mut offset = 0;
-        let mut writer = IOFormatter::new(f);
+        disassemble_chunk(&mut IOFormatter::new(f), self).map_err(|_| fmt::Error)
+    }
+}
+
+/** Disassembles the chunk of a function, followed by those of the functions defined in it */
+pub fn disassemble_function(f: &mut dyn Write, function: &LoxFunction) -> io::Result<()> {
+    writeln!(f, "== {function} ==")?;
+    disassemble_chunk(f, &function.chunk)?;
 
-        while offset < self.len() {
-            offset = debug_instruction(&mut writer, self, offset);
+    for value in &function.chunk.values {
+        if let LoxValue::Object(LoxObject::Function(nested)) = value {
+            writeln!(f)?;
+            disassemble_function(f, nested)?;
         }
-        Ok(())
     }
+
+    Ok(())
+}
+
+fn disassemble_chunk(mut f: &mut dyn Write, chunk: &Chunk) -> io::Result<()> {
+    let mut offset = 0;
+    while offset < chunk.len() {
+        offset = debug_instruction(&mut f, chunk, offset)?;
+    }
+    Ok(())
 }
 
-pub fn debug_instruction(f: &mut impl Write, chunk: &Chunk, offset: usize) -> usize {
-    write!(f, "{offset:04} ").unwrap();
+/** Writes the instruction at the given offset, returning the offset of the next one */
+pub fn debug_instruction(f: &mut impl Write, chunk: &Chunk, offset: usize) -> io::Result<usize> {
+    write!(f, "{offset:04} ")?;
 
-    // Print out the line number, or a vertical bar if it's the same
-    // line as the previous instruction
-    if offset > 0 && chunk.lines[offset] == chunk.lines[offset - 1] {
-        write!(f, "   | ").unwrap();
+    // Print out the line and column, with a vertical bar instead
+    // of the line if it's the same as the previous instruction's
+    let position = chunk.position(offset);
+    let column = position.column;
+    if offset > 0 && chunk.line(offset - 1) == position.line {
+        write!(f, "   |:{column:<3} ")?;
     } else {
-        let line = chunk.lines[offset];
-        write!(f, "{line:>4} ").unwrap();
+        write!(f, "{:>4}:{column:<3} ", position.line)?;
     }
 
     let opcode = chunk[offset];
+    let name = opcode_name(opcode);
     match opcode {
-        OpCodes::OP_RETURN => simple_op(f, "OP_RETURN", offset),
-        OpCodes::OP_CONSTANT => constant_op(f, "OP_CONSTANT", chunk, offset),
-        OpCodes::OP_NEGATE => simple_op(f, "OP_NEGATE", offset),
-        OpCodes::OP_ADD => simple_op(f, "OP_ADD", offset),
-        OpCodes::OP_SUBSTRACT => simple_op(f, "OP_SUBSTRACT", offset),
-        OpCodes::OP_MULTIPLY => simple_op(f, "OP_MULTIPLY", offset),
-        OpCodes::OP_DIVIDE => simple_op(f, "OP_DIVIDE", offset),
-        OpCodes::OP_NULL => simple_op(f, "OP_NULL", offset),
-        OpCodes::OP_TRUE => simple_op(f, "OP_TRUE", offset),
-        OpCodes::OP_FALSE => simple_op(f, "OP_FALSE", offset),
-        OpCodes::OP_NOT => simple_op(f, "OP_NOT", offset),
-        OpCodes::OP_EQUAL => simple_op(f, "OP_EQUAL", offset),
-        OpCodes::OP_LESS => simple_op(f, "OP_LESS", offset),
-        OpCodes::OP_GREATER => simple_op(f, "OP_GREATER", offset),
+        OpCodes::OP_RETURN => simple_op(f, name, offset),
+        OpCodes::OP_CONSTANT => constant_op(f, name, chunk, offset, false),
+        OpCodes::OP_CONSTANT_LONG => constant_op(f, name, chunk, offset, true),
+        OpCodes::OP_NEGATE => simple_op(f, name, offset),
+        OpCodes::OP_ADD => simple_op(f, name, offset),
+        OpCodes::OP_SUBSTRACT => simple_op(f, name, offset),
+        OpCodes::OP_MULTIPLY => simple_op(f, name, offset),
+        OpCodes::OP_DIVIDE => simple_op(f, name, offset),
+        OpCodes::OP_MODULO => simple_op(f, name, offset),
+        OpCodes::OP_CALL => byte_op(f, name, chunk, offset),
+        OpCodes::OP_CLOSURE => closure_op(f, name, chunk, offset, false),
+        OpCodes::OP_CLOSURE_LONG => closure_op(f, name, chunk, offset, true),
+        OpCodes::OP_GET_UPVALUE => byte_op(f, name, chunk, offset),
+        OpCodes::OP_SET_UPVALUE => byte_op(f, name, chunk, offset),
+        OpCodes::OP_CLOSE_UPVALUE => simple_op(f, name, offset),
+        OpCodes::OP_CLASS => constant_op(f, name, chunk, offset, false),
+        OpCodes::OP_CLASS_LONG => constant_op(f, name, chunk, offset, true),
+        OpCodes::OP_GET_PROPERTY => constant_op(f, name, chunk, offset, false),
+        OpCodes::OP_GET_PROPERTY_LONG => constant_op(f, name, chunk, offset, true),
+        OpCodes::OP_SET_PROPERTY => constant_op(f, name, chunk, offset, false),
+        OpCodes::OP_SET_PROPERTY_LONG => constant_op(f, name, chunk, offset, true),
+        OpCodes::OP_METHOD => constant_op(f, name, chunk, offset, false),
+        OpCodes::OP_METHOD_LONG => constant_op(f, name, chunk, offset, true),
+        OpCodes::OP_INVOKE => invoke_op(f, name, chunk, offset, false),
+        OpCodes::OP_INVOKE_LONG => invoke_op(f, name, chunk, offset, true),
+        OpCodes::OP_DUP => simple_op(f, name, offset),
+        OpCodes::OP_INHERIT => simple_op(f, name, offset),
+        OpCodes::OP_GET_SUPER => constant_op(f, name, chunk, offset, false),
+        OpCodes::OP_GET_SUPER_LONG => constant_op(f, name, chunk, offset, true),
+        OpCodes::OP_SUPER_INVOKE => invoke_op(f, name, chunk, offset, false),
+        OpCodes::OP_SUPER_INVOKE_LONG => invoke_op(f, name, chunk, offset, true),
+        OpCodes::OP_LIST => byte_op(f, name, chunk, offset),
+        OpCodes::OP_GET_INDEX => simple_op(f, name, offset),
+        OpCodes::OP_SET_INDEX => simple_op(f, name, offset),
+        OpCodes::OP_DUP2 => simple_op(f, name, offset),
+        OpCodes::OP_NULL => simple_op(f, name, offset),
+        OpCodes::OP_TRUE => simple_op(f, name, offset),
+        OpCodes::OP_FALSE => simple_op(f, name, offset),
+        OpCodes::OP_NOT => simple_op(f, name, offset),
+        OpCodes::OP_EQUAL => simple_op(f, name, offset),
+        OpCodes::OP_LESS => simple_op(f, name, offset),
+        OpCodes::OP_GREATER => simple_op(f, name, offset),
+        OpCodes::OP_POP => simple_op(f, name, offset),
+        OpCodes::OP_DEFINE_GLOBAL => constant_op(f, name, chunk, offset, false),
+        OpCodes::OP_DEFINE_GLOBAL_LONG => constant_op(f, name, chunk, offset, true),
+        OpCodes::OP_GET_GLOBAL => constant_op(f, name, chunk, offset, false),
+        OpCodes::OP_GET_GLOBAL_LONG => constant_op(f, name, chunk, offset, true),
+        OpCodes::OP_SET_GLOBAL => constant_op(f, name, chunk, offset, false),
+        OpCodes::OP_SET_GLOBAL_LONG => constant_op(f, name, chunk, offset, true),
+        OpCodes::OP_GET_LOCAL => byte_op(f, name, chunk, offset),
+        OpCodes::OP_SET_LOCAL => byte_op(f, name, chunk, offset),
+        OpCodes::OP_JUMP => jump_op(f, name, true, chunk, offset),
+        OpCodes::OP_JUMP_IF_FALSE => jump_op(f, name, true, chunk, offset),
+        OpCodes::OP_LOOP => jump_op(f, name, false, chunk, offset),
         _ => panic!("Unknown opcode: {opcode}"),
     }
 }
 
-fn simple_op(f: &mut impl Write, name: &str, offset: usize) -> usize {
-    writeln!(f, "{name}").unwrap();
-    offset + 1
+/** Returns the name of an opcode as shown by the disassembler */
+pub fn opcode_name(opcode: u8) -> &'static str {
+    match opcode {
+        OpCodes::OP_RETURN => "OP_RETURN",
+        OpCodes::OP_CONSTANT => "OP_CONSTANT",
+        OpCodes::OP_CONSTANT_LONG => "OP_CONSTANT_LONG",
+        OpCodes::OP_NEGATE => "OP_NEGATE",
+        OpCodes::OP_ADD => "OP_ADD",
+        OpCodes::OP_SUBSTRACT => "OP_SUBSTRACT",
+        OpCodes::OP_MULTIPLY => "OP_MULTIPLY",
+        OpCodes::OP_DIVIDE => "OP_DIVIDE",
+        OpCodes::OP_MODULO => "OP_MODULO",
+        OpCodes::OP_CALL => "OP_CALL",
+        OpCodes::OP_CLOSURE => "OP_CLOSURE",
+        OpCodes::OP_CLOSURE_LONG => "OP_CLOSURE_LONG",
+        OpCodes::OP_GET_UPVALUE => "OP_GET_UPVALUE",
+        OpCodes::OP_SET_UPVALUE => "OP_SET_UPVALUE",
+        OpCodes::OP_CLOSE_UPVALUE => "OP_CLOSE_UPVALUE",
+        OpCodes::OP_CLASS => "OP_CLASS",
+        OpCodes::OP_CLASS_LONG => "OP_CLASS_LONG",
+        OpCodes::OP_GET_PROPERTY => "OP_GET_PROPERTY",
+        OpCodes::OP_GET_PROPERTY_LONG => "OP_GET_PROPERTY_LONG",
+        OpCodes::OP_SET_PROPERTY => "OP_SET_PROPERTY",
+        OpCodes::OP_SET_PROPERTY_LONG => "OP_SET_PROPERTY_LONG",
+        OpCodes::OP_METHOD => "OP_METHOD",
+        OpCodes::OP_METHOD_LONG => "OP_METHOD_LONG",
+        OpCodes::OP_INVOKE => "OP_INVOKE",
+        OpCodes::OP_INVOKE_LONG => "OP_INVOKE_LONG",
+        OpCodes::OP_DUP => "OP_DUP",
+        OpCodes::OP_INHERIT => "OP_INHERIT",
+        OpCodes::OP_GET_SUPER => "OP_GET_SUPER",
+        OpCodes::OP_GET_SUPER_LONG => "OP_GET_SUPER_LONG",
+        OpCodes::OP_SUPER_INVOKE => "OP_SUPER_INVOKE",
+        OpCodes::OP_SUPER_INVOKE_LONG => "OP_SUPER_INVOKE_LONG",
+        OpCodes::OP_LIST => "OP_LIST",
+        OpCodes::OP_GET_INDEX => "OP_GET_INDEX",
+        OpCodes::OP_SET_INDEX => "OP_SET_INDEX",
+        OpCodes::OP_DUP2 => "OP_DUP2",
+        OpCodes::OP_NULL => "OP_NULL",
+        OpCodes::OP_TRUE => "OP_TRUE",
+        OpCodes::OP_FALSE => "OP_FALSE",
+        OpCodes::OP_NOT => "OP_NOT",
+        OpCodes::OP_EQUAL => "OP_EQUAL",
+        OpCodes::OP_LESS => "OP_LESS",
+        OpCodes::OP_GREATER => "OP_GREATER",
+        OpCodes::OP_POP => "OP_POP",
+        OpCodes::OP_DEFINE_GLOBAL => "OP_DEFINE_GLOBAL",
+        OpCodes::OP_DEFINE_GLOBAL_LONG => "OP_DEFINE_GLOBAL_LONG",
+        OpCodes::OP_GET_GLOBAL => "OP_GET_GLOBAL",
+        OpCodes::OP_GET_GLOBAL_LONG => "OP_GET_GLOBAL_LONG",
+        OpCodes::OP_SET_GLOBAL => "OP_SET_GLOBAL",
+        OpCodes::OP_SET_GLOBAL_LONG => "OP_SET_GLOBAL_LONG",
+        OpCodes::OP_GET_LOCAL => "OP_GET_LOCAL",
+        OpCodes::OP_SET_LOCAL => "OP_SET_LOCAL",
+        OpCodes::OP_JUMP => "OP_JUMP",
+        OpCodes::OP_JUMP_IF_FALSE => "OP_JUMP_IF_FALSE",
+        OpCodes::OP_LOOP => "OP_LOOP",
+        _ => "OP_UNKNOWN",
+    }
+}
+
+/** Returns the offset of the instruction that follows the one at the given offset */
+pub fn next_instruction(chunk: &Chunk, offset: usize) -> usize {
+    // Decoding the instruction is the same as disassembling it, minus the output
+    debug_instruction(&mut sink(), chunk, offset).expect("Writing to a sink can't fail")
+}
+
+fn simple_op(f: &mut impl Write, name: &str, offset: usize) -> io::Result<usize> {
+    writeln!(f, "{name}")?;
+    Ok(offset + 1)
+}
+
+fn byte_op(f: &mut impl Write, name: &str, chunk: &Chunk, offset: usize) -> io::Result<usize> {
+    let slot = chunk[offset + 1];
+    writeln!(f, "{name:<16} {slot:4}")?;
+    Ok(offset + 2)
+}
+
+fn jump_op(f: &mut impl Write, name: &str, forward: bool, chunk: &Chunk, offset: usize) -> io::Result<usize> {
+    let jump = u16::from_be_bytes([chunk[offset + 1], chunk[offset + 2]]) as usize;
+    let next = offset + 3;
+    let target = if forward { next + jump } else { next - jump };
+    writeln!(f, "{name:<16} {offset:4} -> {target}")?;
+    Ok(offset + 3)
+}
+
+/** Reads the constant index of an instruction, which takes 3 bytes in the long form
+of the instruction and 1 byte otherwise. Also returns the offset right after it. */
+fn constant_index(chunk: &Chunk, offset: usize, long: bool) -> (usize, usize) {
+    if long {
+        let value_ix = u32::from_be_bytes([0, chunk[offset + 1], chunk[offset + 2], chunk[offset + 3]]);
+        (value_ix as usize, offset + 4)
+    } else {
+        (chunk[offset + 1] as usize, offset + 2)
+    }
+}
+
+fn constant_op(f: &mut impl Write, name: &str, chunk: &Chunk, offset: usize, long: bool) -> io::Result<usize> {
+    let (value_ix, next) = constant_index(chunk, offset, long);
+    let value = &chunk.values[value_ix];
+    writeln!(f, "{name:<16} {value_ix:4} '{value}'")?;
+    Ok(next)
 }
 
-fn constant_op(f: &mut impl Write, name: &str, chunk: &Chunk, offset: usize) -> usize {
-    let value_ix = chunk[offset + 1];
-    let value = &chunk.values[value_ix as usize];
-    writeln!(f, "{name:<16} {value_ix:4} '{value}'").unwrap();
-    offset + 2
+fn invoke_op(f: &mut impl Write, name: &str, chunk: &Chunk, offset: usize, long: bool) -> io::Result<usize> {
+    let (value_ix, next) = constant_index(chunk, offset, long);
+    let arg_count = chunk[next];
+    let value = &chunk.values[value_ix];
+    writeln!(f, "{name:<16} ({arg_count} args) {value_ix:4} '{value}'")?;
+    Ok(next + 1)
+}
+
+fn closure_op(f: &mut impl Write, name: &str, chunk: &Chunk, offset: usize, long: bool) -> io::Result<usize> {
+    let (value_ix, _) = constant_index(chunk, offset, long);
+    let mut offset = constant_op(f, name, chunk, offset, long)?;
+
+    // The closure instruction is followed by the captured variables
+    let upvalues = match &chunk.values[value_ix] {
+        LoxValue::Object(LoxObject::Function(func)) => func.upvalue_count,
+        _ => 0,
+    };
+
+    for _ in 0..upvalues {
+        let kind = if chunk[offset] == 1 { "local" } else { "upvalue" };
+        let index = chunk[offset + 1];
+        writeln!(f, "{offset:04}    |                         {kind} {index}")?;
+        offset += 2;
+    }
+
+    Ok(offset)
 }
 
 ////////////////////////////////////////////////////////////////////////////
@@ -79,22 +260,66 @@ impl Display for LoxValue {
 
 impl Debug for LoxValue {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "{self}")
+        match self {
+            Self::Object(o) => write!(f, "{o:?}"),
+            _ => write!(f, "{self}"),
+        }
     }
 }
 
 impl Display for LoxObject {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
-            Self::String(s) => write!(f, "\"{s}\""),
+            Self::String(s) => write!(f, "{s}"),
+            Self::Function(func) => write!(f, "{func}"),
+            Self::Closure(closure) => write!(f, "{}", closure.function),
+            Self::Class(class) => write!(f, "{}", class.name),
+            Self::Instance(instance) => write!(f, "{} instance", instance.class.name),
+            Self::BoundMethod(bound) => write!(f, "{}", bound.method.function),
+            Self::Native(native) => write!(f, "<native fn {}>", native.name),
+            Self::List(list) => fmt_list(list, f, &mut Vec::new()),
         }
     }
 }
 
 impl Debug for LoxObject {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "{self}")
+        match self {
+            Self::String(s) => write!(f, "\"{s}\""),
+            _ => write!(f, "{self}"),
+        }
+    }
+}
+
+impl Display for LoxFunction {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match &self.name {
+            Some(name) => write!(f, "<fn {name}>"),
+            None => write!(f, "<script>"),
+        }
+    }
+}
+
+/** Writes the items of a list. Lists can contain themselves, directly or through
+other lists, so the ones that are already being written are shown as `[...]` */
+fn fmt_list(list: &Rc<LoxList>, f: Fmtr, outer: &mut Vec<*const LoxList>) -> Result {
+    if outer.contains(&Rc::as_ptr(list)) {
+        return write!(f, "[...]");
+    }
+
+    outer.push(Rc::as_ptr(list));
+    write!(f, "[")?;
+    for (i, item) in list.items.borrow().iter().enumerate() {
+        if i > 0 {
+            write!(f, ", ")?;
+        }
+        match item {
+            LoxValue::Object(LoxObject::List(inner)) => fmt_list(inner, f, outer)?,
+            _ => write!(f, "{item:?}")?,
+        }
     }
+    outer.pop();
+    write!(f, "]")
 }
 
 ////////////////////////////////////////////////////////////////////////////
@@ -113,7 +338,7 @@ impl<'a, 'b> IOFormatter<'a, 'b> {
 impl<'a, 'b> Write for IOFormatter<'a, 'b> {
     fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
         self.f.write_str(&String::from_utf8_lossy(buf))
-            .map_err(|err| std::io::Error::new(std::io::ErrorKind::Other, err))?;
+            .map_err(std::io::Error::other)?;
 
         Ok(buf.len())
     }
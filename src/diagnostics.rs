@@ -0,0 +1,168 @@
+//! Rendering of errors as rustc-style diagnostics, which show
+//! the offending source code with the error location underlined.
+
+use std::fmt::Write;
+use std::ops::Range;
+use crate::errors::{CompileError, LoxError};
+
+const RED: &str = "\x1b[1;31m";
+const BLUE: &str = "\x1b[1;34m";
+const BOLD: &str = "\x1b[1m";
+const RESET: &str = "\x1b[0m";
+
+/// A location in the source code underlined by a diagnostic
+struct Annotation<'a> {
+    // 0-based line index and column (in characters)
+    line: usize,
+    column: usize,
+    width: usize,
+    primary: bool,
+    message: &'a str,
+}
+
+struct Style {
+    color: bool,
+}
+
+impl LoxError {
+    /** Renders the error for humans, showing the source code where compile errors happened.
+    `path` identifies the source in the error locations, and `color` enables ANSI colors. */
+    pub fn render(&self, source: &str, path: &str, color: bool) -> String {
+        match self {
+            LoxError::Compile(errors) => errors.iter()
+                .map(|err| err.render(source, path, color))
+                .collect::<Vec<_>>()
+                .join("\n\n"),
+            LoxError::Runtime(err) => err.to_string(),
+        }
+    }
+}
+
+impl CompileError {
+    /** Renders the error for humans, see `LoxError::render` */
+    pub fn render(&self, source: &str, path: &str, color: bool) -> String {
+        let style = Style { color };
+        let mut annotations = vec![Annotation::new(source, &self.span, true, "")];
+        for label in &self.labels {
+            annotations.push(Annotation::new(source, &label.span, false, &label.message));
+        }
+
+        let mut lines: Vec<usize> = annotations.iter().map(|ann| ann.line).collect();
+        lines.sort_unstable();
+        lines.dedup();
+
+        // The gutter must fit the largest line number
+        let gutter = (lines[lines.len() - 1] + 1).to_string().len();
+        let pad = " ".repeat(gutter);
+        let primary = &annotations[0];
+
+        let mut out = String::new();
+        writeln!(out, "{}: {}", style.paint(RED, "error"), style.paint(BOLD, &self.message)).unwrap();
+        writeln!(out, "{pad}{} {path}:{}:{}", style.paint(BLUE, "-->"), primary.line + 1, primary.column + 1).unwrap();
+        write!(out, "{pad} {}", style.paint(BLUE, "|")).unwrap();
+
+        let source_lines: Vec<&str> = source.split('\n').collect();
+        for (i, &line) in lines.iter().enumerate() {
+            if i > 0 && line - lines[i - 1] > 1 {
+                write!(out, "\n{}", style.paint(BLUE, "...")).unwrap();
+            }
+
+            // Tabs are replaced so that the underlines stay aligned
+            let text = source_lines[line].trim_end_matches('\r').replace('\t', " ");
+            let number = format!("{:>gutter$} |", line + 1);
+            write!(out, "\n{} {text}", style.paint(BLUE, &number)).unwrap();
+
+            let mut on_line: Vec<&Annotation> = annotations.iter().filter(|ann| ann.line == line).collect();
+            on_line.sort_by_key(|ann| ann.column);
+            render_underlines(&mut out, &on_line, &pad, &style);
+        }
+
+        out
+    }
+}
+
+/** Writes the rows under a source line: one with the underlines for all the annotations on
+the line and the message of the last one, and then the other messages from right to left */
+fn render_underlines(out: &mut String, annotations: &[&Annotation], pad: &str, style: &Style) {
+    let gutter = format!("\n{pad} {} ", style.paint(BLUE, "|"));
+
+    out.push_str(&gutter);
+    let mut cursor = 0;
+    for ann in annotations {
+        // Overlapping annotations keep the underline of the leftmost one
+        if ann.column < cursor { continue }
+
+        let marker = if ann.primary { "^" } else { "-" };
+        out.push_str(&" ".repeat(ann.column - cursor));
+        out.push_str(&style.paint(ann.color(), &marker.repeat(ann.width)));
+        cursor = ann.column + ann.width;
+    }
+
+    let (last, rest) = annotations.split_last().unwrap();
+    if !last.message.is_empty() {
+        write!(out, " {}", style.paint(last.color(), last.message)).unwrap();
+    }
+
+    let rest: Vec<&&Annotation> = rest.iter().filter(|ann| !ann.message.is_empty()).collect();
+    for i in (0 .. rest.len()).rev() {
+        // A row connecting all the pending messages, then the message itself
+        out.push_str(&gutter);
+        render_connectors(out, &rest[..= i], style);
+
+        out.push_str(&gutter);
+        let cursor = render_connectors(out, &rest[.. i], style);
+        out.push_str(&" ".repeat(rest[i].column - cursor));
+        out.push_str(&style.paint(rest[i].color(), rest[i].message));
+    }
+}
+
+/** Writes a vertical bar under the start of each annotation, returning the column after the last one */
+fn render_connectors(out: &mut String, annotations: &[&&Annotation], style: &Style) -> usize {
+    let mut cursor = 0;
+    for ann in annotations {
+        if ann.column < cursor { continue }
+
+        out.push_str(&" ".repeat(ann.column - cursor));
+        out.push_str(&style.paint(ann.color(), "|"));
+        cursor = ann.column + 1;
+    }
+
+    cursor
+}
+
+impl<'a> Annotation<'a> {
+    fn new(source: &str, span: &Range<usize>, primary: bool, message: &'a str) -> Self {
+        let mut start = span.start.min(source.len());
+        // Errors at the end of the file point right after its last character
+        if start == source.len() {
+            start = source.trim_end().len();
+        }
+
+        let line_start = source[.. start].rfind('\n').map_or(0, |i| i + 1);
+        let line_end = source[start ..].find('\n').map_or(source.len(), |i| start + i);
+        // Spans of tokens covering several lines are cut at the end of the first one
+        let end = span.end.clamp(start, line_end);
+
+        Self {
+            line: source[.. line_start].matches('\n').count(),
+            column: source[line_start .. start].chars().count(),
+            width: source.get(start .. end).map_or(1, |s| s.chars().count()).max(1),
+            primary,
+            message,
+        }
+    }
+
+    fn color(&self) -> &'static str {
+        if self.primary { RED } else { BLUE }
+    }
+}
+
+impl Style {
+    fn paint(&self, code: &str, text: &str) -> String {
+        if self.color {
+            format!("{code}{text}{RESET}")
+        } else {
+            text.to_owned()
+        }
+    }
+}
@@ -0,0 +1,209 @@
+//! Errors produced while compiling or running Lox code.
+
+use std::error::Error;
+use std::fmt::{Display, Formatter, Result};
+use std::ops::Range;
+
+/// Number of frames shown at each end of long stack traces
+const TRACE_EDGE_FRAMES: usize = 10;
+
+pub type InterpretResult<T = ()> = std::result::Result<T, LoxError>;
+
+#[derive(Clone, Debug)]
+pub enum LoxError {
+    // All the errors found in the source code
+    Compile(Vec<CompileError>),
+    Runtime(RuntimeError),
+}
+
+#[derive(Clone, Debug)]
+pub struct CompileError {
+    pub message: String,
+    pub line: u32,
+    // 1-based, in characters
+    pub column: u32,
+    // Byte offsets of the offending code within the source
+    pub span: Range<usize>,
+    pub site: ErrorSite,
+    // Other locations related to the error
+    pub labels: Vec<Label>,
+}
+
+/// A secondary location related to a compile error, such as
+/// the opening delimiter when the closing one is missing
+#[derive(Clone, Debug)]
+pub struct Label {
+    pub message: String,
+    pub line: u32,
+    pub column: u32,
+    pub span: Range<usize>,
+}
+
+/// What a compile error points at, used to describe its location
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ErrorSite {
+    Token(String),
+    End,
+    // Errors from the scanner, for which there is no valid token
+    Unknown,
+}
+
+#[derive(Clone, Debug)]
+pub struct RuntimeError {
+    pub message: String,
+    // Call stack at the moment of the error, innermost call first.
+    // It's empty for errors raised before any code starts running.
+    pub trace: Vec<TraceFrame>,
+}
+
+/// Error returned by a native function
+#[derive(Clone, Debug)]
+pub enum NativeError {
+    // Reported as a runtime error at the call to the native
+    Message(String),
+    // Raised by Lox code that the native called, which is passed on
+    // as it is, with the calls that led to the native added to its trace
+    Runtime(RuntimeError),
+}
+
+#[derive(Clone, Debug)]
+pub struct TraceFrame {
+    // None for the top-level script
+    pub function: Option<String>,
+    pub line: u32,
+    pub column: u32,
+}
+
+impl LoxError {
+    /** Exit code for the process, following the convention of sysexits.h */
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            LoxError::Compile(_) => 65,
+            LoxError::Runtime(_) => 70,
+        }
+    }
+}
+
+impl From<RuntimeError> for LoxError {
+    fn from(err: RuntimeError) -> Self {
+        LoxError::Runtime(err)
+    }
+}
+
+impl From<String> for NativeError {
+    fn from(message: String) -> Self {
+        NativeError::Message(message)
+    }
+}
+
+impl From<&str> for NativeError {
+    fn from(message: &str) -> Self {
+        NativeError::Message(message.to_owned())
+    }
+}
+
+impl From<RuntimeError> for NativeError {
+    fn from(err: RuntimeError) -> Self {
+        NativeError::Runtime(err)
+    }
+}
+
+impl From<LoxError> for NativeError {
+    fn from(err: LoxError) -> Self {
+        match err {
+            // Natives may compile code, whose errors have no trace to add to
+            LoxError::Compile(_) => NativeError::Message(err.to_string()),
+            LoxError::Runtime(err) => NativeError::Runtime(err),
+        }
+    }
+}
+
+impl RuntimeError {
+    pub fn new(message: String, trace: Vec<TraceFrame>) -> Self {
+        Self { message, trace }
+    }
+
+    /** Line of the code being run when the error happened, if any */
+    pub fn line(&self) -> Option<u32> {
+        self.trace.first().map(|frame| frame.line)
+    }
+}
+
+impl Display for LoxError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result {
+        match self {
+            LoxError::Compile(errors) => {
+                for (i, err) in errors.iter().enumerate() {
+                    if i > 0 {
+                        writeln!(f)?;
+                    }
+                    write!(f, "{err}")?;
+                }
+                Ok(())
+            },
+            LoxError::Runtime(err) => write!(f, "{err}"),
+        }
+    }
+}
+
+impl Display for CompileError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result {
+        write!(f, "[Line {}] Error", self.line)?;
+
+        match &self.site {
+            ErrorSite::Token(lexeme) => write!(f, " at '{lexeme}'")?,
+            ErrorSite::End => write!(f, " at end")?,
+            ErrorSite::Unknown => {},
+        }
+
+        write!(f, ": {}", self.message)
+    }
+}
+
+impl Display for RuntimeError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result {
+        write!(f, "{}", self.message)?;
+
+        // Deep recursion can produce huge traces, in which case only
+        // the innermost and outermost calls are shown
+        if self.trace.len() > 2 * TRACE_EDGE_FRAMES {
+            let skipped = self.trace.len() - 2 * TRACE_EDGE_FRAMES;
+            for frame in &self.trace[.. TRACE_EDGE_FRAMES] {
+                write!(f, "\n{frame}")?;
+            }
+            write!(f, "\n... {skipped} more frames")?;
+            for frame in &self.trace[self.trace.len() - TRACE_EDGE_FRAMES ..] {
+                write!(f, "\n{frame}")?;
+            }
+        } else {
+            for frame in &self.trace {
+                write!(f, "\n{frame}")?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl Display for NativeError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result {
+        match self {
+            NativeError::Message(message) => write!(f, "{message}"),
+            NativeError::Runtime(err) => write!(f, "{err}"),
+        }
+    }
+}
+
+impl Display for TraceFrame {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result {
+        match &self.function {
+            Some(name) => write!(f, "[line {}] in {name}()", self.line),
+            None => write!(f, "[line {}] in script.", self.line),
+        }
+    }
+}
+
+impl Error for LoxError {}
+impl Error for CompileError {}
+impl Error for RuntimeError {}
+impl Error for NativeError {}
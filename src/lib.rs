@@ -0,0 +1,30 @@
+//! Lox bytecode compiler and interpreter.
+//!
+//! Programs are run by a [`VM`], which keeps its global variables between
+//! runs. Globals can also be read and written from Rust, and Lox functions
+//! can be called with Rust arguments through [`VM::call_function`].
+//!
+//! Objects are exposed as the types held by the variants of [`LoxObject`], so
+//! that Rust code can read the items of a [`LoxList`], the fields of a
+//! [`LoxInstance`] and so on. The bytecode and captured variables of functions
+//! stay private to the VM, and only it can create functions and closures.
+//!
+//! [`is_incomplete`] is meant for front ends that read code line by line, like
+//! the REPL of the `rlox` binary, to know when to ask for more input before
+//! compiling it. It only scans the code, so it's much cheaper than compiling.
+
+mod debug;
+mod diagnostics;
+mod errors;
+mod parsing;
+mod runtime;
+mod scanning;
+mod values;
+
+pub use errors::{CompileError, ErrorSite, InterpretResult, Label, LoxError, NativeError, RuntimeError, TraceFrame};
+pub use runtime::{Script, TraceFormat, Tracer, VM};
+pub use scanning::is_incomplete;
+pub use values::{
+    LoxObject, LoxString, LoxValue, LoxFunction, LoxClosure,
+    LoxClass, LoxInstance, LoxBoundMethod, LoxNative, NativeFn, LoxList,
+};
@@ -1,30 +1,113 @@
-mod debug;
-mod parsing;
-mod runtime;
-mod scanning;
-mod values;
+mod cli;
+mod repl;
 
 use std::env;
 use std::fs::read_to_string;
+use std::io::{read_to_string as read_all, stderr, stdin, stdout, IsTerminal};
 use std::process::exit;
 
-use runtime::VM;
+use cli::{Cli, Command, Script, USAGE};
+use rlox::{LoxError, LoxValue, Tracer, VM};
+
+// Exit codes, following the convention of sysexits.h
+const EXIT_USAGE: i32 = 64;
+const EXIT_NO_INPUT: i32 = 66;
+const EXIT_IO_ERROR: i32 = 74;
 
 fn main() {
-    let args: Vec<String> = env::args().collect();
-    if args.len() != 2 {
-        println!("Usage: rlox <file.lox>");
-        exit(1);
-    } else {
-        run_from_file(&args[1]);
-    }
+    let cli = match cli::parse_args(env::args().skip(1)) {
+        Ok(cli) => cli,
+        Err(msg) => {
+            eprintln!("{msg}\nRun 'rlox --help' to see the usage.");
+            exit(EXIT_USAGE);
+        },
+    };
+
+    exit(run(cli));
 }
 
-fn run_from_file(filename: &str) -> ! {
-    let file_contents = read_to_string(filename)
-                            .expect("Could not find/read the provided file.");
+/** Runs the command given in the arguments, returning the exit code */
+fn run(cli: Cli) -> i32 {
     let mut vm = VM::new();
-    let result = vm.interpret(&file_contents);
+    if let Some(format) = cli.trace {
+        // The trace goes to stderr so that it doesn't mix with the output of the program
+        vm.set_tracer(Some(Tracer::new(Box::new(stderr())).format(format)));
+    }
+
+    match cli.command {
+        Command::Help => {
+            println!("{USAGE}");
+            0
+        },
+        Command::Version => {
+            println!("rlox {}", env!("CARGO_PKG_VERSION"));
+            0
+        },
+        Command::Repl => {
+            vm.set_global("args", Vec::<LoxValue>::new());
+            match repl::run(vm) {
+                Ok(()) => 0,
+                Err(err) => {
+                    eprintln!("REPL error: {err}");
+                    EXIT_IO_ERROR
+                },
+            }
+        },
+        Command::Run { script, args } => with_source(&script, |source, name| {
+            let args: Vec<LoxValue> = args.into_iter().map(LoxValue::from).collect();
+            vm.set_global("args", args);
+            report_result(vm.interpret(source), source, name)
+        }),
+        Command::Check(script) => with_source(&script, |source, name| {
+            report_result(vm.compile(source).map(|_| ()), source, name)
+        }),
+        Command::Disassemble(script) => with_source(&script, |source, name| {
+            let compiled = match vm.compile(source) {
+                Ok(compiled) => compiled,
+                Err(err) => return report_result(Err(err), source, name),
+            };
+
+            match compiled.disassemble(&mut stdout()) {
+                Ok(()) => 0,
+                Err(err) => {
+                    eprintln!("Could not write the bytecode: {err}");
+                    EXIT_IO_ERROR
+                },
+            }
+        }),
+    }
+}
+
+/** Reads the source code of a script and passes it to a function along with its name */
+fn with_source(script: &Script, f: impl FnOnce(&str, &str) -> i32) -> i32 {
+    let (source, name) = match script {
+        Script::File(path) => (read_to_string(path), path.as_str()),
+        Script::Stdin => (read_all(stdin()), "<stdin>"),
+        Script::Eval(code) => (Ok(code.clone()), "<eval>"),
+    };
+
+    match source {
+        Ok(source) => f(&source, name),
+        Err(err) => {
+            eprintln!("Could not read '{name}': {err}");
+            EXIT_NO_INPUT
+        },
+    }
+}
+
+fn report_result(result: Result<(), LoxError>, source: &str, name: &str) -> i32 {
+    match result {
+        Ok(()) => 0,
+        Err(err) => {
+            report_error(&err, source, name);
+            err.exit_code()
+        },
+    }
+}
 
-    exit(result.exit_code());
+/** Prints an error to stderr, pointing at the source code where it happened */
+fn report_error(err: &LoxError, source: &str, path: &str) {
+    // Colors are only used for humans, see https://no-color.org
+    let color = stderr().is_terminal() && env::var_os("NO_COLOR").is_none();
+    eprintln!("{}", err.render(source, path, color));
 }
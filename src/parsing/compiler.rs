@@ -0,0 +1,148 @@
+//! Per-function compilation state that the parser keeps track of
+//! while emitting bytecode, such as the local variables in scope.
+
+use crate::values::LoxFunction;
+
+/// Max. number of locals that can be in scope at the same time
+/// within a function, as their slots are addressed with a single byte.
+pub const MAX_LOCALS: usize = u8::MAX as usize + 1;
+/// Max. number of variables that a function can capture from enclosing ones
+pub const MAX_UPVALUES: usize = u8::MAX as usize + 1;
+/// Max. number of constants in a function, as the longest index to them has 3 bytes
+pub const MAX_CONSTANTS: usize = 1 << 24;
+
+pub struct Compiler<'src> {
+    pub function: LoxFunction,
+    pub kind: FunctionKind,
+    pub locals: Vec<Local<'src>>,
+    pub upvalues: Vec<Upvalue>,
+    pub scope_depth: usize,
+    pub loops: Vec<Loop<'src>>,
+}
+
+#[derive(Copy, Clone, PartialEq, Eq)]
+pub enum FunctionKind {
+    Script,
+    Function,
+    Method,
+    Initializer,
+}
+
+pub struct Local<'src> {
+    pub name: &'src str,
+    // The depth is None while the variable is declared but its
+    // initializer hasn't been compiled yet
+    pub depth: Option<usize>,
+    // Whether the variable is captured by a closure, and thus
+    // must be moved to the heap when it goes out of scope
+    pub is_captured: bool,
+}
+
+#[derive(Copy, Clone, PartialEq, Eq)]
+pub struct Upvalue {
+    // Index of the captured variable, either as a local in the stack
+    // frame of the enclosing function, or as one of its upvalues
+    pub index: u8,
+    pub is_local: bool,
+}
+
+/// Information about a class declaration being compiled
+pub struct ClassCompiler {
+    pub has_superclass: bool,
+}
+
+pub struct Loop<'src> {
+    pub label: Option<&'src str>,
+    // Position of the code that starts the next iteration, where `continue` jumps to
+    pub start: usize,
+    // Scope depth outside of the loop body, locals deeper than
+    // this must be popped when jumping out of the body
+    pub scope_depth: usize,
+    // Offsets of the `break` jumps that must be patched once
+    // the end of the loop is known
+    pub breaks: Vec<usize>,
+}
+
+impl<'src> Compiler<'src> {
+    pub fn new(kind: FunctionKind, name: Option<&str>) -> Self {
+        let mut locals = Vec::with_capacity(MAX_LOCALS);
+        // The first stack slot of every call frame holds the function being called,
+        // so we claim it with a local that can't be referenced by user code.
+        // For methods, it holds the instance the method is called on instead.
+        let slot_name = match kind {
+            FunctionKind::Method | FunctionKind::Initializer => "this",
+            _ => "",
+        };
+        locals.push(Local { name: slot_name, depth: Some(0), is_captured: false });
+
+        Self {
+            function: LoxFunction::new(name),
+            kind,
+            locals,
+            upvalues: vec![],
+            scope_depth: 0,
+            loops: vec![],
+        }
+    }
+
+    pub fn begin_loop(&mut self, label: Option<&'src str>, start: usize) {
+        let scope_depth = self.scope_depth;
+        self.loops.push(Loop { label, start, scope_depth, breaks: vec![] });
+    }
+
+    /** Finds the index of the innermost loop with the given label */
+    pub fn resolve_loop(&self, label: &str) -> Option<usize> {
+        self.loops.iter().rposition(|lp| lp.label == Some(label))
+    }
+
+    pub fn add_local(&mut self, name: &'src str) {
+        self.locals.push(Local { name, depth: None, is_captured: false });
+    }
+
+    pub fn mark_initialized(&mut self) {
+        if self.scope_depth == 0 {
+            // Globals aren't tracked as locals
+            return;
+        }
+
+        let depth = self.scope_depth;
+        if let Some(local) = self.locals.last_mut() {
+            local.depth = Some(depth);
+        }
+    }
+
+    /** Checks whether a variable with the given name was already declared in the current scope */
+    pub fn is_declared_in_scope(&self, name: &str) -> bool {
+        self.locals.iter()
+            .rev()
+            .take_while(|local| local.depth.is_none_or(|d| d >= self.scope_depth))
+            .any(|local| local.name == name)
+    }
+
+    /** Finds the stack slot of the innermost local with the given name, and whether it's initialized */
+    pub fn resolve_local(&self, name: &str) -> Option<(u8, bool)> {
+        self.locals.iter()
+            .enumerate()
+            .rev()
+            .find(|(_, local)| local.name == name)
+            .map(|(slot, local)| (slot as u8, local.depth.is_some()))
+    }
+
+    /** Registers a variable captured by this function, returning its index
+    in the list of upvalues, or None if there are too many of them */
+    pub fn add_upvalue(&mut self, index: u8, is_local: bool) -> Option<u8> {
+        let upvalue = Upvalue { index, is_local };
+
+        // Closures capturing the same variable multiple times reuse the same upvalue
+        if let Some(ix) = self.upvalues.iter().position(|uv| *uv == upvalue) {
+            return Some(ix as u8);
+        }
+
+        if self.upvalues.len() == MAX_UPVALUES {
+            return None;
+        }
+
+        self.upvalues.push(upvalue);
+        Some((self.upvalues.len() - 1) as u8)
+    }
+}
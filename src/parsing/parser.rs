@@ -1,40 +1,453 @@
-use crate::runtime::{Chunk, OpCodes};
+use std::mem::replace;
+use std::rc::Rc;
+use crate::errors::{CompileError, ErrorSite, Label};
+use crate::runtime::{Chunk, OpCodes, Position};
 use crate::scanning::{Scanner, Token, TokenType};
-use crate::values::{LoxValue, LoxObject};
+use crate::values::{LoxValue, LoxObject, LoxFunction, StringTable};
+use super::compiler::{ClassCompiler, Compiler, FunctionKind, MAX_CONSTANTS, MAX_LOCALS};
 use super::utils::{Precedence, Precs};
 use TokenType::*;
 
-pub struct Parser<'src, 'chk> {
+pub struct Parser<'src> {
     scanner: Scanner<'src>,
     previous: Token<'src>,
     current: Token<'src>,
-    chunk: Option<&'chk mut Chunk>,
-    had_error: bool,
+    // State of the function currently being compiled, and of
+    // the ones that enclose it, from outermost to innermost
+    compiler: Compiler<'src>,
+    enclosing: Vec<Compiler<'src>>,
+    // Class declarations being compiled, from outermost to innermost
+    classes: Vec<ClassCompiler>,
+    errors: Vec<CompileError>,
     panic_mode: bool,
+    // Whether an expression at the end of the script is returned
+    // as its result, instead of being discarded
+    returns_last_expr: bool,
+    // Table where the strings used by the code are interned
+    strings: &'src mut StringTable,
 }
 
-impl<'src, 'chk> Parser<'src, 'chk> {
-    pub fn new(source: &'src str) -> Self {
+impl<'src> Parser<'src> {
+    pub fn new(source: &'src str, strings: &'src mut StringTable) -> Self {
         Self {
             scanner: Scanner::new(source),
             previous: Token::default(),
             current: Token::default(),
-            chunk: None,
-            had_error: false,
+            compiler: Compiler::new(FunctionKind::Script, None),
+            enclosing: vec![],
+            classes: vec![],
+            errors: vec![],
             panic_mode: false,
+            returns_last_expr: false,
+            strings,
         }
     }
 
-    pub fn compile(&mut self, chunk: &'chk mut Chunk) -> bool {
-        self.chunk = Some(chunk);
+    /** Makes the script return the value of the expression it ends with, if any.
+    The semicolon after that expression becomes optional. */
+    pub fn return_last_expression(mut self) -> Self {
+        self.returns_last_expr = true;
+        self
+    }
+
+    /** Compiles the source code into a function that runs the top-level script,
+    or returns all the errors found in it */
+    pub fn compile(mut self) -> Result<LoxFunction, Vec<CompileError>> {
         self.consume();
+
+        while !self.matches(Eof) {
+            self.declaration();
+        }
+
+        let function = self.finish().function;
+        if self.errors.is_empty() { Ok(function) } else { Err(self.errors) }
+    }
+
+    //////////////////////////////////////////////////////////////////////////////////////////
+    // Statement parsing methods
+
+    fn declaration(&mut self) {
+        if self.matches(Class) {
+            self.class_declaration();
+        } else if self.matches(Fn) {
+            self.fn_declaration();
+        } else if self.matches(Let) {
+            self.let_declaration();
+        } else {
+            // Only statements directly in the script, and not those nested in
+            // an if or a loop, can provide the value that the script returns
+            let at_top_level = self.enclosing.is_empty() && self.compiler.scope_depth == 0;
+            self.statement(at_top_level);
+        }
+
+        if self.panic_mode {
+            self.synchronize();
+        }
+    }
+
+    fn class_declaration(&mut self) {
+        self.consume_if(Identifier, "Expected class name.");
+        let class_name = self.previous;
+        let name_ix = self.identifier_constant(class_name);
+        self.declare_variable();
+
+        self.emit_indexed(OpCodes::OP_CLASS, name_ix);
+        self.define_variable(name_ix);
+
+        self.classes.push(ClassCompiler { has_superclass: false });
+
+        if self.matches(Less) {
+            self.consume_if(Identifier, "Expected superclass name.");
+            self.variable(false);
+
+            if class_name.literal == self.previous.literal {
+                self.error_at_previous("A class can't inherit from itself.");
+            }
+
+            // The superclass is stored in a local named `super`, which methods
+            // capture as an upvalue to resolve `super` calls. A new scope is needed
+            // so that sibling classes don't clash with each other's `super`.
+            self.begin_scope();
+            self.compiler.add_local("super");
+            self.define_variable(0);
+
+            self.named_variable(class_name, false);
+            self.emit_byte(OpCodes::OP_INHERIT);
+            self.classes.last_mut().unwrap().has_superclass = true;
+        }
+
+        // Load the class back onto the stack, so that methods can be bound to it
+        self.named_variable(class_name, false);
+
+        self.consume_if(LeftBrace, "Expected '{' before class body.");
+        while !self.check(RightBrace) && !self.check(Eof) {
+            self.method();
+        }
+        self.consume_if(RightBrace, "Expected '}' after class body.");
+
+        self.emit_byte(OpCodes::OP_POP);
+
+        if self.classes.pop().unwrap().has_superclass {
+            self.end_scope();
+        }
+    }
+
+    fn method(&mut self) {
+        self.consume_if(Identifier, "Expected method name.");
+        let name_ix = self.identifier_constant(self.previous);
+
+        let kind = if self.previous.literal == "init" {
+            FunctionKind::Initializer
+        } else {
+            FunctionKind::Method
+        };
+
+        self.function(kind);
+        self.emit_indexed(OpCodes::OP_METHOD, name_ix);
+    }
+
+    fn fn_declaration(&mut self) {
+        let global = self.parse_variable("Expected function name.");
+        // Functions can be referenced inside their own body to allow
+        // recursion, so they're marked as initialized straight away
+        self.compiler.mark_initialized();
+        self.function(FunctionKind::Function);
+        self.define_variable(global);
+    }
+
+    /** Compiles the parameters and body of a function, leaving it on the stack */
+    fn function(&mut self, kind: FunctionKind) {
+        let name = self.previous.literal;
+        let outer = replace(&mut self.compiler, Compiler::new(kind, Some(name)));
+        self.enclosing.push(outer);
+        self.begin_scope();
+
+        self.consume_if(LeftParen, "Expected '(' after function name.");
+        if !self.check(RightParen) {
+            loop {
+                if self.compiler.function.arity == u8::MAX {
+                    self.error_at_current("Can't have more than 255 parameters.");
+                } else {
+                    self.compiler.function.arity += 1;
+                }
+
+                let param = self.parse_variable("Expected parameter name.");
+                self.define_variable(param);
+
+                if !self.matches(Comma) {
+                    break;
+                }
+            }
+        }
+        self.consume_if(RightParen, "Expected ')' after parameters.");
+        self.consume_if(LeftBrace, "Expected '{' before function body.");
+        self.block();
+
+        // There's no need to end the scope, since the whole
+        // compiler for this function is discarded
+        let Compiler { mut function, upvalues, .. } = self.finish();
+        function.upvalue_count = upvalues.len();
+
+        // The closure instruction is followed by a pair of bytes for every
+        // captured variable, so the VM knows where to find them
+        let ix = self.make_constant(LoxValue::Object(LoxObject::Function(Rc::new(function))));
+        self.emit_indexed(OpCodes::OP_CLOSURE, ix);
+        for upvalue in upvalues {
+            self.emit_bytes(upvalue.is_local as u8, upvalue.index);
+        }
+    }
+
+    fn let_declaration(&mut self) {
+        let global = self.parse_variable("Expected variable name.");
+
+        if self.matches(Equal) {
+            self.expression();
+        } else {
+            // Variables declared without an initializer default to null
+            self.emit_byte(OpCodes::OP_NULL);
+        }
+
+        self.consume_if(Semicolon, "Expected ';' after variable declaration.");
+        self.define_variable(global);
+    }
+
+    fn statement(&mut self, at_top_level: bool) {
+        if self.check(Identifier) && self.peek_next().kind == Colon {
+            self.labeled_statement();
+        } else if self.matches(If) {
+            self.if_statement();
+        } else if self.matches(While) {
+            self.while_statement(None);
+        } else if self.matches(For) {
+            self.for_statement(None);
+        } else if self.matches(Return) {
+            self.return_statement();
+        } else if self.matches(Break) {
+            self.break_statement();
+        } else if self.matches(Continue) {
+            self.continue_statement();
+        } else if self.matches(LeftBrace) {
+            self.begin_scope();
+            self.block();
+            self.end_scope();
+        } else {
+            self.expression_statement(at_top_level);
+        }
+    }
+
+    fn block(&mut self) {
+        let opening = self.previous;
+        while !self.check(RightBrace) && !self.check(Eof) {
+            self.declaration();
+        }
+
+        self.consume_closing(opening, RightBrace, "Expected '}' after block.");
+    }
+
+    fn if_statement(&mut self) {
+        self.consume_if(LeftParen, "Expected '(' after 'if'.");
         self.expression();
-        self.finish();
-        !self.had_error
+        self.consume_if(RightParen, "Expected ')' after condition.");
+
+        // The condition is left on the stack by OP_JUMP_IF_FALSE, so
+        // it must be popped at the beginning of both branches
+        let then_jump = self.emit_jump(OpCodes::OP_JUMP_IF_FALSE);
+        self.emit_byte(OpCodes::OP_POP);
+        self.statement(false);
+
+        let else_jump = self.emit_jump(OpCodes::OP_JUMP);
+        self.patch_jump(then_jump);
+        self.emit_byte(OpCodes::OP_POP);
+
+        if self.matches(Else) {
+            self.statement(false);
+        }
+
+        self.patch_jump(else_jump);
+    }
+
+    fn return_statement(&mut self) {
+        if self.compiler.kind == FunctionKind::Script {
+            self.error_at_previous("Can't return from top-level code.");
+        }
+
+        if self.matches(Semicolon) {
+            self.emit_return();
+        } else {
+            if self.compiler.kind == FunctionKind::Initializer {
+                self.error_at_previous("Can't return a value from an initializer.");
+            }
+
+            self.expression();
+            self.consume_if(Semicolon, "Expected ';' after return value.");
+            self.emit_byte(OpCodes::OP_RETURN);
+        }
+    }
+
+    fn labeled_statement(&mut self) {
+        self.consume();
+        let label = self.previous.literal;
+        // Otherwise, the outer loop couldn't be targeted from inside the inner one
+        if self.compiler.resolve_loop(label).is_some() {
+            self.error_at_previous(&format!("Label '{label}' is already used by an enclosing loop."));
+        }
+        self.consume();
+
+        if self.matches(While) {
+            self.while_statement(Some(label));
+        } else if self.matches(For) {
+            self.for_statement(Some(label));
+        } else {
+            self.error_at_current("Expected a loop after label.");
+        }
+    }
+
+    fn while_statement(&mut self, label: Option<&'src str>) {
+        let loop_start = self.current_chunk().len();
+
+        self.consume_if(LeftParen, "Expected '(' after 'while'.");
+        self.expression();
+        self.consume_if(RightParen, "Expected ')' after condition.");
+
+        let exit_jump = self.emit_jump(OpCodes::OP_JUMP_IF_FALSE);
+        self.emit_byte(OpCodes::OP_POP);
+        self.loop_body(label, loop_start);
+        self.emit_loop(loop_start);
+
+        self.patch_jump(exit_jump);
+        self.emit_byte(OpCodes::OP_POP);
+        self.patch_breaks();
+    }
+
+    fn for_statement(&mut self, label: Option<&'src str>) {
+        // Variables declared in the initializer are scoped to the loop
+        self.begin_scope();
+        self.consume_if(LeftParen, "Expected '(' after 'for'.");
+
+        // Initializer clause
+        if self.matches(Semicolon) {
+            // No initializer
+        } else if self.matches(Let) {
+            self.let_declaration();
+        } else {
+            self.expression_statement(false);
+        }
+
+        let mut loop_start = self.current_chunk().len();
+
+        // Condition clause
+        let mut exit_jump = None;
+        if !self.matches(Semicolon) {
+            self.expression();
+            self.consume_if(Semicolon, "Expected ';' after loop condition.");
+
+            exit_jump = Some(self.emit_jump(OpCodes::OP_JUMP_IF_FALSE));
+            self.emit_byte(OpCodes::OP_POP);
+        }
+
+        // Increment clause. It appears before the body in the source, but
+        // must run after it, so we jump over it and then loop back to it
+        // at the end of the body.
+        if !self.matches(RightParen) {
+            let body_jump = self.emit_jump(OpCodes::OP_JUMP);
+            let increment_start = self.current_chunk().len();
+
+            self.expression();
+            self.emit_byte(OpCodes::OP_POP);
+            self.consume_if(RightParen, "Expected ')' after for clauses.");
+
+            self.emit_loop(loop_start);
+            loop_start = increment_start;
+            self.patch_jump(body_jump);
+        }
+
+        self.loop_body(label, loop_start);
+        self.emit_loop(loop_start);
+
+        if let Some(exit_jump) = exit_jump {
+            self.patch_jump(exit_jump);
+            self.emit_byte(OpCodes::OP_POP);
+        }
+
+        self.patch_breaks();
+        self.end_scope();
+    }
+
+    /** Compiles the body of a loop whose next iteration starts at `start`.
+    The loop is left open in the compiler, so that its pending breaks
+    can be patched once the exit of the loop has been emitted. */
+    fn loop_body(&mut self, label: Option<&'src str>, start: usize) {
+        self.compiler.begin_loop(label, start);
+        self.statement(false);
+    }
+
+    /** Closes the innermost loop, making all its breaks jump to the current position */
+    fn patch_breaks(&mut self) {
+        let lp = self.compiler.loops.pop().unwrap();
+        for jump in lp.breaks {
+            self.patch_jump(jump);
+        }
+    }
+
+    fn break_statement(&mut self) {
+        if let Some(ix) = self.resolve_loop() {
+            let depth = self.compiler.loops[ix].scope_depth;
+            self.discard_locals(depth);
+
+            let jump = self.emit_jump(OpCodes::OP_JUMP);
+            self.compiler.loops[ix].breaks.push(jump);
+        }
+
+        self.consume_if(Semicolon, "Expected ';' after 'break'.");
+    }
+
+    fn continue_statement(&mut self) {
+        if let Some(ix) = self.resolve_loop() {
+            let (depth, start) = (self.compiler.loops[ix].scope_depth, self.compiler.loops[ix].start);
+            self.discard_locals(depth);
+            self.emit_loop(start);
+        }
+
+        self.consume_if(Semicolon, "Expected ';' after 'continue'.");
+    }
+
+    /** Finds the loop targeted by a `break` or `continue` statement, which is either
+    the one with the provided label, or the innermost one if there is no label */
+    fn resolve_loop(&mut self) -> Option<usize> {
+        let keyword = self.previous.literal;
+
+        if self.matches(Identifier) {
+            let label = self.previous.literal;
+            let ix = self.compiler.resolve_loop(label);
+            if ix.is_none() {
+                self.error_at_previous(&format!("No enclosing loop with label '{label}'."));
+            }
+            ix
+        } else if self.compiler.loops.is_empty() {
+            self.error_at_previous(&format!("Can't use '{keyword}' outside of a loop."));
+            None
+        } else {
+            Some(self.compiler.loops.len() - 1)
+        }
+    }
+
+    fn expression_statement(&mut self, at_top_level: bool) {
+        // Expressions used as statements leave their value on the stack,
+        // which must be discarded since nobody is going to use it
+        self.expression();
+
+        if self.returns_last_expr && at_top_level && self.is_last_statement() {
+            self.matches(Semicolon);
+            self.emit_byte(OpCodes::OP_RETURN);
+            return;
+        }
+
+        self.consume_if(Semicolon, "Expected ';' after expression.");
+        self.emit_byte(OpCodes::OP_POP);
     }
 
     //////////////////////////////////////////////////////////////////////////////////////////
-    /// Expression parsing methods
+    // Expression parsing methods
 
     fn parse_precedence(&mut self, prec: Precedence) {
         self.consume();
@@ -48,8 +461,13 @@ impl<'src, 'chk> Parser<'src, 'chk> {
             return;
         }
 
+        // Only allow assignment if we are parsing an expression with a low
+        // enough precedence, otherwise something like `a + b = c` would
+        // be parsed as `a + (b = c)`
+        let can_assign = prec <= Precs::ASSIGN;
+
         // Parse the infix expression
-        prefix_fn.unwrap()(self);
+        prefix_fn.unwrap()(self, can_assign);
 
         // At this point, the next token may be a binary operator. If it is and
         // it has a higher precedence than the expression we are currently
@@ -59,7 +477,13 @@ impl<'src, 'chk> Parser<'src, 'chk> {
             self.consume();
             // Parse the expression
             let infix_fn = Self::get_rule(self.previous.kind).1.unwrap();
-            infix_fn(self);
+            infix_fn(self, can_assign);
+        }
+
+        // If we reach this point with a pending '=', nothing consumed it
+        // because the left-hand side of the expression is not assignable
+        if can_assign && (self.matches(Equal) || self.compound_assignment(can_assign).is_some()) {
+            self.error_at_previous("Invalid assignment target.");
         }
     }
 
@@ -67,7 +491,7 @@ impl<'src, 'chk> Parser<'src, 'chk> {
         self.parse_precedence(Precs::ASSIGN);
     }
 
-    pub(super) fn literal(&mut self) {
+    pub(super) fn literal(&mut self, _can_assign: bool) {
         match self.previous.kind {
             Null => self.emit_byte(OpCodes::OP_NULL),
             True => self.emit_byte(OpCodes::OP_TRUE),
@@ -76,65 +500,237 @@ impl<'src, 'chk> Parser<'src, 'chk> {
         }
     }
 
-    pub(super) fn number(&mut self) {
+    pub(super) fn number(&mut self, _can_assign: bool) {
         // We can unwrap safely because the token wouldn't be of type Number
         // if the format wasn't correct.
         let number = self.previous.literal.parse().unwrap();
         self.emit_constant(LoxValue::Number(number));
     }
 
-    pub(super) fn string(&mut self) {
+    pub(super) fn string(&mut self, _can_assign: bool) {
         let string = self.previous.literal;
         let trimmed = &string[1..string.len() - 1];
-        self.emit_constant(LoxValue::Object(LoxObject::String(trimmed.to_owned())));
+        let string = self.strings.intern(trimmed);
+        self.emit_constant(LoxValue::Object(LoxObject::String(string)));
+    }
+
+    pub(super) fn variable(&mut self, can_assign: bool) {
+        self.named_variable(self.previous, can_assign);
     }
 
-    pub(super) fn grouping(&mut self) {
+    pub(super) fn grouping(&mut self, _can_assign: bool) {
+        let opening = self.previous;
         self.expression();
-        self.consume_if(RightParen, "Expected closing ')' after expression.");
+        self.consume_closing(opening, RightParen, "Expected closing ')' after expression.");
     }
 
-    pub(super) fn unary(&mut self) {
-        let op = self.previous.kind;
+    pub(super) fn unary(&mut self, _can_assign: bool) {
+        let operator = self.previous;
 
         // Compile the expression ahead first
         self.parse_precedence(Precs::UNARY);
 
         // Emit the right instruction according to the operand
-        match op {
-            Minus => self.emit_byte(OpCodes::OP_NEGATE),
-            Not => self.emit_byte(OpCodes::OP_NOT),
+        match operator.kind {
+            Minus => self.emit_byte_at(OpCodes::OP_NEGATE, operator),
+            Not => self.emit_byte_at(OpCodes::OP_NOT, operator),
             _ => unreachable!(),
         }
     }
 
-    pub(super) fn binary(&mut self) {
+    pub(super) fn binary(&mut self, _can_assign: bool) {
         // The left-side expression has already been compiled
-        let op = self.previous.kind;
-        let (_, _, precedence) = Self::get_rule(op);
+        let operator = self.previous;
+        let (_, _, precedence) = Self::get_rule(operator.kind);
 
         // Compile the right-side expression with a higher precedence
         // to ensure left associativity
         self.parse_precedence(precedence + 1);
 
         // Emit the right instructions according to the operand
-        match op {
-            Plus => self.emit_byte(OpCodes::OP_ADD),
-            Minus => self.emit_byte(OpCodes::OP_SUBSTRACT),
-            Asterisk => self.emit_byte(OpCodes::OP_MULTIPLY),
-            Slash => self.emit_byte(OpCodes::OP_DIVIDE),
-            EqualEqual => self.emit_byte(OpCodes::OP_EQUAL),
-            NotEqual => self.emit_bytes(OpCodes::OP_EQUAL, OpCodes::OP_NOT),
-            Less => self.emit_byte(OpCodes::OP_LESS),
-            LessEqual => self.emit_bytes(OpCodes::OP_GREATER, OpCodes::OP_NOT),
-            Greater => self.emit_byte(OpCodes::OP_GREATER),
-            GreaterEqual => self.emit_bytes(OpCodes::OP_LESS, OpCodes::OP_NOT),
+        let bytes: &[u8] = match operator.kind {
+            Plus => &[OpCodes::OP_ADD],
+            Minus => &[OpCodes::OP_SUBSTRACT],
+            Asterisk => &[OpCodes::OP_MULTIPLY],
+            Slash => &[OpCodes::OP_DIVIDE],
+            Percent => &[OpCodes::OP_MODULO],
+            EqualEqual => &[OpCodes::OP_EQUAL],
+            NotEqual => &[OpCodes::OP_EQUAL, OpCodes::OP_NOT],
+            Less => &[OpCodes::OP_LESS],
+            LessEqual => &[OpCodes::OP_GREATER, OpCodes::OP_NOT],
+            Greater => &[OpCodes::OP_GREATER],
+            GreaterEqual => &[OpCodes::OP_LESS, OpCodes::OP_NOT],
             _ => unreachable!()
+        };
+
+        for &byte in bytes {
+            self.emit_byte_at(byte, operator);
+        }
+    }
+
+    pub(super) fn call(&mut self, _can_assign: bool) {
+        let opening = self.previous;
+        let arg_count = self.argument_list();
+        self.emit_byte_at(OpCodes::OP_CALL, opening);
+        self.emit_byte_at(arg_count, opening);
+    }
+
+    pub(super) fn list(&mut self, _can_assign: bool) {
+        let opening = self.previous;
+        let mut count: u8 = 0;
+
+        if !self.check(RightBracket) {
+            loop {
+                self.expression();
+                if count == u8::MAX {
+                    self.error_at_previous("Can't have more than 255 items in a list literal.");
+                } else {
+                    count += 1;
+                }
+
+                if !self.matches(Comma) {
+                    break;
+                }
+            }
+        }
+
+        self.consume_closing(opening, RightBracket, "Expected ']' after list items.");
+        self.emit_bytes(OpCodes::OP_LIST, count);
+    }
+
+    pub(super) fn index(&mut self, can_assign: bool) {
+        let opening = self.previous;
+        self.expression();
+        self.consume_closing(opening, RightBracket, "Expected ']' after index.");
+
+        if can_assign && self.matches(Equal) {
+            self.expression();
+            self.emit_byte_at(OpCodes::OP_SET_INDEX, opening);
+        } else if let Some(op) = self.compound_assignment(can_assign) {
+            // The list and the index are duplicated so that the
+            // expressions that produce them are only evaluated once
+            self.emit_byte(OpCodes::OP_DUP2);
+            self.emit_byte_at(OpCodes::OP_GET_INDEX, opening);
+            self.expression();
+            self.emit_byte(op);
+            self.emit_byte_at(OpCodes::OP_SET_INDEX, opening);
+        } else {
+            self.emit_byte_at(OpCodes::OP_GET_INDEX, opening);
         }
     }
 
+    pub(super) fn dot(&mut self, can_assign: bool) {
+        self.consume_if(Identifier, "Expected property name after '.'.");
+        let property = self.previous;
+        let name = self.identifier_constant(property);
+
+        if can_assign && self.matches(Equal) {
+            self.expression();
+            self.emit_indexed_at(OpCodes::OP_SET_PROPERTY, name, property);
+        } else if let Some(op) = self.compound_assignment(can_assign) {
+            // The instance is duplicated so that the expression
+            // that produces it is only evaluated once
+            self.emit_byte(OpCodes::OP_DUP);
+            self.emit_indexed(OpCodes::OP_GET_PROPERTY, name);
+            self.expression();
+            self.emit_byte(op);
+            self.emit_indexed_at(OpCodes::OP_SET_PROPERTY, name, property);
+        } else if self.matches(LeftParen) {
+            // Calling a method directly, we can skip creating a bound method
+            let arg_count = self.argument_list();
+            self.emit_indexed_at(OpCodes::OP_INVOKE, name, property);
+            self.emit_byte_at(arg_count, property);
+        } else {
+            self.emit_indexed(OpCodes::OP_GET_PROPERTY, name);
+        }
+    }
+
+    pub(super) fn this(&mut self, _can_assign: bool) {
+        if self.classes.is_empty() {
+            self.error_at_previous("Can't use 'this' outside of a class.");
+            return;
+        }
+
+        // `this` is resolved like any other variable, but can't be assigned to
+        self.variable(false);
+    }
+
+    pub(super) fn super_(&mut self, _can_assign: bool) {
+        match self.classes.last() {
+            None => self.error_at_previous("Can't use 'super' outside of a class."),
+            Some(class) if !class.has_superclass => {
+                self.error_at_previous("Can't use 'super' in a class with no superclass.");
+            },
+            _ => {},
+        }
+
+        self.consume_if(Dot, "Expected '.' after 'super'.");
+        self.consume_if(Identifier, "Expected superclass method name.");
+        let name = self.identifier_constant(self.previous);
+
+        // Both the instance and the superclass are needed to look up and bind the method
+        self.named_variable(self.synthetic_token("this"), false);
+
+        if self.matches(LeftParen) {
+            let arg_count = self.argument_list();
+            self.named_variable(self.synthetic_token("super"), false);
+            self.emit_indexed(OpCodes::OP_SUPER_INVOKE, name);
+            self.emit_byte(arg_count);
+        } else {
+            self.named_variable(self.synthetic_token("super"), false);
+            self.emit_indexed(OpCodes::OP_GET_SUPER, name);
+        }
+    }
+
+    fn argument_list(&mut self) -> u8 {
+        let opening = self.previous;
+        let mut count: u8 = 0;
+
+        if !self.check(RightParen) {
+            loop {
+                self.expression();
+                if count == u8::MAX {
+                    self.error_at_previous("Can't have more than 255 arguments.");
+                } else {
+                    count += 1;
+                }
+
+                if !self.matches(Comma) {
+                    break;
+                }
+            }
+        }
+
+        self.consume_closing(opening, RightParen, "Expected ')' after arguments.");
+        count
+    }
+
+    pub(super) fn and(&mut self, _can_assign: bool) {
+        // The left-side operand is already on the stack. If it's falsey, it is
+        // the value of the whole expression and the right side is skipped.
+        let end_jump = self.emit_jump(OpCodes::OP_JUMP_IF_FALSE);
+
+        self.emit_byte(OpCodes::OP_POP);
+        self.parse_precedence(Precs::BOOL_AND);
+
+        self.patch_jump(end_jump);
+    }
+
+    pub(super) fn or(&mut self, _can_assign: bool) {
+        // If the left-side operand is truthy, it is the value of the
+        // whole expression and the right side is skipped.
+        let else_jump = self.emit_jump(OpCodes::OP_JUMP_IF_FALSE);
+        let end_jump = self.emit_jump(OpCodes::OP_JUMP);
+
+        self.patch_jump(else_jump);
+        self.emit_byte(OpCodes::OP_POP);
+        self.parse_precedence(Precs::BOOL_OR);
+
+        self.patch_jump(end_jump);
+    }
+
     //////////////////////////////////////////////////////////////////////////////////////////
-    /// Token processing methods
+    // Token processing methods
 
     /** Unconditionally consumes the next token from the scanner */
     fn consume(&mut self) {
@@ -146,6 +742,33 @@ impl<'src, 'chk> Parser<'src, 'chk> {
         }
     }
 
+    /** Consumes the next token if it is of the given type, returning whether it was */
+    fn matches(&mut self, kind: TokenType) -> bool {
+        let matches = self.check(kind);
+        if matches { self.consume() }
+        matches
+    }
+
+    /** Checks whether the next token is of the given type, without consuming it */
+    fn check(&self, kind: TokenType) -> bool {
+        self.current.kind == kind
+    }
+
+    /** Checks whether the current statement is the last one in the source code */
+    fn is_last_statement(&self) -> bool {
+        self.check(Eof) || (self.check(Semicolon) && self.peek_next().kind == Eof)
+    }
+
+    /** Returns the token after the next one, without consuming anything */
+    fn peek_next(&self) -> Token<'src> {
+        self.scanner.clone().scan_next_token()
+    }
+
+    /** Creates an identifier token that doesn't appear in the source code */
+    fn synthetic_token(&self, literal: &'static str) -> Token<'src> {
+        Token { kind: Identifier, literal, ..self.previous }
+    }
+
     /** Consumes the next token from the scanner if it is of the expected type */
     fn consume_if(&mut self, expected: TokenType, msg: &str) {
         if self.current.kind == expected {
@@ -155,12 +778,36 @@ impl<'src, 'chk> Parser<'src, 'chk> {
         }
     }
 
+    /** Consumes the token that closes the given opening delimiter,
+    pointing at the opening one in the error if it's missing */
+    fn consume_closing(&mut self, opening: Token, expected: TokenType, msg: &str) {
+        if self.current.kind == expected {
+            self.consume();
+        } else if matches!(opening.kind, LeftParen | LeftBracket | LeftBrace) {
+            let label = Label {
+                message: format!("opening '{}' here", opening.literal),
+                line: opening.line,
+                column: opening.column,
+                span: opening.offset .. opening.offset + opening.literal.len(),
+            };
+            self.error_with_labels(self.current, msg, vec![label]);
+        } else {
+            self.error_at_current(msg);
+        }
+    }
+
     //////////////////////////////////////////////////////////////////////////////////////////
-    /// Bytecode compiling methods
+    // Bytecode compiling methods
 
     fn emit_byte(&mut self, byte: u8) {
-        let line = self.previous.line;
-        self.current_chunk().write_byte(byte, line);
+        self.emit_byte_at(byte, self.previous);
+    }
+
+    /** Emits a byte located at the given token, instead of the last one consumed. Used for
+    operations that come after their operands, so that their errors point to the operator. */
+    fn emit_byte_at(&mut self, byte: u8, token: Token) {
+        let position = Position { line: token.line, column: token.column };
+        self.current_chunk().write_byte(byte, position);
     }
 
     fn emit_bytes(&mut self, byte1: u8, byte2: u8) {
@@ -168,25 +815,284 @@ impl<'src, 'chk> Parser<'src, 'chk> {
         self.emit_byte(byte2);
     }
 
+    /** Emits a jump instruction with a placeholder offset, returning
+    the position of the offset so that it can be patched later */
+    fn emit_jump(&mut self, op: u8) -> usize {
+        self.emit_byte(op);
+        self.emit_bytes(0xFF, 0xFF);
+        self.current_chunk().len() - 2
+    }
+
+    /** Fills in the offset of a previously emitted jump, so
+    that it lands right after the last emitted instruction */
+    fn patch_jump(&mut self, offset: usize) {
+        // -2 to account for the jump offset itself
+        let jump = self.current_chunk().len() - offset - 2;
+        if jump > u16::MAX as usize {
+            self.error_at_previous("Too much code to jump over.");
+        }
+
+        let [hi, lo] = (jump as u16).to_be_bytes();
+        let chunk = self.current_chunk();
+        chunk.bytes[offset] = hi;
+        chunk.bytes[offset + 1] = lo;
+    }
+
+    /** Emits a backwards jump to the given position */
+    fn emit_loop(&mut self, loop_start: usize) {
+        self.emit_byte(OpCodes::OP_LOOP);
+
+        // +2 to account for the loop offset itself
+        let jump = self.current_chunk().len() - loop_start + 2;
+        if jump > u16::MAX as usize {
+            self.error_at_previous("Loop body too large.");
+        }
+
+        let [hi, lo] = (jump as u16).to_be_bytes();
+        self.emit_bytes(hi, lo);
+    }
+
     fn emit_constant(&mut self, val: LoxValue) {
+        let ix = self.make_constant(val);
+        self.emit_indexed(OpCodes::OP_CONSTANT, ix);
+    }
+
+    fn emit_indexed(&mut self, op: u8, ix: usize) {
+        self.emit_indexed_at(op, ix, self.previous);
+    }
+
+    /** Emits an instruction that takes the index of a constant, using its long form
+    with a 3-byte index for constants that don't fit in the usual 1-byte one */
+    fn emit_indexed_at(&mut self, op: u8, ix: usize, token: Token) {
+        if ix <= u8::MAX as usize {
+            self.emit_byte_at(op, token);
+            self.emit_byte_at(ix as u8, token);
+        } else {
+            self.emit_byte_at(OpCodes::long_form(op), token);
+            let [_, hi, mid, lo] = (ix as u32).to_be_bytes();
+            for byte in [hi, mid, lo] {
+                self.emit_byte_at(byte, token);
+            }
+        }
+    }
+
+    /** Adds a value to the constants table, returning its index */
+    fn make_constant(&mut self, val: LoxValue) -> usize {
         let ix = self.current_chunk().add_constant(val);
-        if ix > u8::MAX as usize {
-            panic!("Max constants reached.");
+        if ix >= MAX_CONSTANTS {
+            self.error_at_previous("Too many constants in one chunk.");
+            return 0;
         }
 
-        self.emit_bytes(OpCodes::OP_CONSTANT, ix as u8);
+        ix
     }
 
-    fn finish(&mut self) {
+    /** Stores the name of a variable in the constants table, returning its index */
+    fn identifier_constant(&mut self, name: Token) -> usize {
+        let name = self.strings.intern(name.literal);
+        self.make_constant(LoxValue::Object(LoxObject::String(name)))
+    }
+
+    //////////////////////////////////////////////////////////////////////////////////////////
+    // Variable and scope handling methods
+
+    /** Consumes a variable name, returning the index of its name in the constants table.
+    Local variables aren't looked up by name, so the returned index is meaningless for them. */
+    fn parse_variable(&mut self, msg: &str) -> usize {
+        self.consume_if(Identifier, msg);
+
+        self.declare_variable();
+        if self.compiler.scope_depth > 0 {
+            return 0;
+        }
+
+        self.identifier_constant(self.previous)
+    }
+
+    /** Registers a new local variable in the current scope, if we're not in the global one */
+    fn declare_variable(&mut self) {
+        if self.compiler.scope_depth == 0 {
+            return;
+        }
+
+        let name = self.previous.literal;
+        if self.compiler.is_declared_in_scope(name) {
+            self.error_at_previous("Already a variable with this name in this scope.");
+        }
+
+        if self.compiler.locals.len() == MAX_LOCALS {
+            self.error_at_previous("Too many local variables in function.");
+            return;
+        }
+
+        self.compiler.add_local(name);
+    }
+
+    fn define_variable(&mut self, global: usize) {
+        if self.compiler.scope_depth > 0 {
+            // Locals already live in their stack slot, we just have to mark
+            // them as ready to be used.
+            self.compiler.mark_initialized();
+        } else {
+            self.emit_indexed(OpCodes::OP_DEFINE_GLOBAL, global);
+        }
+    }
+
+    fn named_variable(&mut self, name: Token, can_assign: bool) {
+        let (get_op, set_op, arg) = match self.compiler.resolve_local(name.literal) {
+            Some((slot, initialized)) => {
+                if !initialized {
+                    self.error_at_previous("Can't read local variable in its own initializer.");
+                }
+                (OpCodes::OP_GET_LOCAL, OpCodes::OP_SET_LOCAL, slot as usize)
+            },
+            None => match self.resolve_upvalue(0, name.literal) {
+                Some(ix) => (OpCodes::OP_GET_UPVALUE, OpCodes::OP_SET_UPVALUE, ix as usize),
+                None => {
+                    let ix = self.identifier_constant(name);
+                    (OpCodes::OP_GET_GLOBAL, OpCodes::OP_SET_GLOBAL, ix)
+                },
+            },
+        };
+
+        // Locals and upvalues always fit in a 1-byte index, globals may need the long form
+        if can_assign && self.matches(Equal) {
+            self.expression();
+            self.emit_indexed(set_op, arg);
+        } else if let Some(op) = self.compound_assignment(can_assign) {
+            // `a += b` is compiled as `a = a + b`
+            self.emit_indexed(get_op, arg);
+            self.expression();
+            self.emit_byte(op);
+            self.emit_indexed(set_op, arg);
+        } else {
+            self.emit_indexed(get_op, arg);
+        }
+    }
+
+    /** Consumes a compound assignment operator if there is one and assignment is allowed,
+    returning the opcode for the arithmetic operation that it performs */
+    fn compound_assignment(&mut self, can_assign: bool) -> Option<u8> {
+        if !can_assign {
+            return None;
+        }
+
+        let op = match self.current.kind {
+            PlusEqual => OpCodes::OP_ADD,
+            MinusEqual => OpCodes::OP_SUBSTRACT,
+            AsteriskEqual => OpCodes::OP_MULTIPLY,
+            SlashEqual => OpCodes::OP_DIVIDE,
+            PercentEqual => OpCodes::OP_MODULO,
+            _ => return None,
+        };
+
+        self.consume();
+        Some(op)
+    }
+
+    /** Looks for a variable captured from an enclosing function by the function `level`
+    levels above the current one, returning its index in that function's upvalues */
+    fn resolve_upvalue(&mut self, level: usize, name: &str) -> Option<u8> {
+        if level == self.enclosing.len() {
+            // This is the top-level script, there's nothing to capture from
+            return None;
+        }
+
+        // Check whether it's a local in the directly enclosing function,
+        // otherwise look for it further up the chain of functions, so that
+        // every function in between captures it too.
+        let (index, is_local) = match self.compiler_at(level + 1).resolve_local(name) {
+            Some((slot, _)) => {
+                self.compiler_at(level + 1).locals[slot as usize].is_captured = true;
+                (slot, true)
+            },
+            None => (self.resolve_upvalue(level + 1, name)?, false),
+        };
+
+        let upvalue = self.compiler_at(level).add_upvalue(index, is_local);
+        if upvalue.is_none() {
+            self.error_at_previous("Too many closure variables in function.");
+        }
+
+        Some(upvalue.unwrap_or(0))
+    }
+
+    /** Returns the compiler for the function `level` levels above the current one */
+    fn compiler_at(&mut self, level: usize) -> &mut Compiler<'src> {
+        match level {
+            0 => &mut self.compiler,
+            _ => {
+                let ix = self.enclosing.len() - level;
+                &mut self.enclosing[ix]
+            },
+        }
+    }
+
+    fn begin_scope(&mut self) {
+        self.compiler.scope_depth += 1;
+    }
+
+    /** Pops the locals declared deeper than the given scope depth from the VM's stack,
+    while keeping them in the compiler. Used when jumping out of a scope early. */
+    fn discard_locals(&mut self, depth: usize) {
+        let ops: Vec<u8> = self.compiler.locals.iter()
+            .rev()
+            .take_while(|local| local.depth.is_some_and(|d| d > depth))
+            .map(|local| Self::discard_op(local.is_captured))
+            .collect();
+
+        for op in ops {
+            self.emit_byte(op);
+        }
+    }
+
+    /** Returns the instruction that discards a local going out of scope. Captured
+    locals are moved to the heap so that closures can still access them. */
+    fn discard_op(is_captured: bool) -> u8 {
+        if is_captured { OpCodes::OP_CLOSE_UPVALUE } else { OpCodes::OP_POP }
+    }
+
+    fn end_scope(&mut self) {
+        self.compiler.scope_depth -= 1;
+
+        // Discard the locals of the scope we're leaving, both
+        // from the compiler and from the VM's stack
+        while self.compiler.locals.last()
+                .is_some_and(|local| local.depth.is_some_and(|d| d > self.compiler.scope_depth)) {
+            let local = self.compiler.locals.pop().unwrap();
+            self.emit_byte(Self::discard_op(local.is_captured));
+        }
+    }
+
+    /** Emits an implicit return at the end of a function */
+    fn emit_return(&mut self) {
+        if self.compiler.kind == FunctionKind::Initializer {
+            // Initializers always return the instance, which lives in slot 0
+            self.emit_bytes(OpCodes::OP_GET_LOCAL, 0);
+        } else {
+            self.emit_byte(OpCodes::OP_NULL);
+        }
+
         self.emit_byte(OpCodes::OP_RETURN);
     }
 
+    /** Finishes compiling the current function, returning its compiler
+    and restoring the compiler of the enclosing function, if any */
+    fn finish(&mut self) -> Compiler<'src> {
+        self.emit_return();
+
+        match self.enclosing.pop() {
+            Some(outer) => replace(&mut self.compiler, outer),
+            None => replace(&mut self.compiler, Compiler::new(FunctionKind::Script, None)),
+        }
+    }
+
     fn current_chunk(&mut self) -> &mut Chunk {
-        self.chunk.as_mut().unwrap()
+        &mut self.compiler.function.chunk
     }
 
     //////////////////////////////////////////////////////////////////////////////////////////
-    /// Error methods
+    // Error methods
     fn error_at_current(&mut self, msg: &str) {
         self.error_at(self.current, msg);
     }
@@ -195,22 +1101,76 @@ impl<'src, 'chk> Parser<'src, 'chk> {
         self.error_at(self.previous, msg);
     }
 
-    fn error_at(&mut self, token: Token, msg: &str) {
-        if self.panic_mode { return }
+    /** Skips tokens until the next statement after an error, so that
+    the errors in the following statements can be reported too */
+    fn synchronize(&mut self) {
+        self.panic_mode = false;
 
-        eprint!("[Line {}] Error", token.line);
+        while !self.check(Eof) {
+            if matches!(self.previous.kind, Semicolon | RightBrace) { return }
 
-        if token.kind == Eof {
-            eprint!(" at end");
-        } else if token.kind != Error {
-            eprint!(" at '{}'", token.literal);
+            match self.current.kind {
+                Class | Fn | Let | For | If | While | Return | Break | Continue => return,
+                _ => self.consume(),
+            }
         }
+    }
+
+    fn error_at(&mut self, token: Token, msg: &str) {
+        self.error_with_labels(token, msg, vec![]);
+    }
+
+    fn error_with_labels(&mut self, token: Token, msg: &str, labels: Vec<Label>) {
+        if self.panic_mode { return }
+
+        let (site, len) = match token.kind {
+            Eof => (ErrorSite::End, 0),
+            // The literal of error tokens is the error message, we
+            // just point at the character where the token starts
+            Error => (ErrorSite::Unknown, 1),
+            _ => (ErrorSite::Token(token.literal.to_owned()), token.literal.len()),
+        };
 
-        eprintln!(": {msg}");
+        self.errors.push(CompileError {
+            message: msg.to_owned(),
+            line: token.line,
+            column: token.column,
+            span: token.offset .. token.offset + len,
+            site,
+            labels,
+        });
 
-        self.had_error = true;
         self.panic_mode = true;
     }
 
 }
 
+#[cfg(test)]
+mod tests {
+    use crate::values::StringTable;
+    use super::Parser;
+
+    fn errors(source: &str) -> Vec<String> {
+        let mut strings = StringTable::new();
+        match Parser::new(source, &mut strings).compile() {
+            Ok(_) => vec![],
+            Err(errors) => errors.into_iter().map(|err| err.message).collect(),
+        }
+    }
+
+    #[test]
+    fn labels_cant_shadow_enclosing_loops() {
+        assert_eq!(
+            errors("a: while (true) { a: while (true) { break a; } }"),
+            ["Label 'a' is already used by an enclosing loop."],
+        );
+        assert_eq!(
+            errors("a: while (true) { b: for (;;) { a: for (;;) {} } }"),
+            ["Label 'a' is already used by an enclosing loop."],
+        );
+
+        // Loops that don't enclose each other, or are in different functions, can share labels
+        assert!(errors("a: while (true) { break a; } a: while (true) { break a; }").is_empty());
+        assert!(errors("a: while (true) { fn f() { a: while (true) { break a; } } break a; }").is_empty());
+    }
+}
@@ -1,34 +1,35 @@
-/** Big declaration chunks that I didn't wanna have around actual parsing logic. */
+//! Big declaration chunks that I didn't wanna have around actual parsing logic.
 
 use crate::scanning::TokenType::{self, *};
 use super::Parser;
 
-pub type ParseFn<'src, 'chk> = Option<fn(&mut Parser<'src, 'chk>)>;
-pub type ParseRule<'src, 'chk> = (ParseFn<'src, 'chk>, ParseFn<'src, 'chk>, Precedence);
+pub type ParseFn<'src> = Option<fn(&mut Parser<'src>, bool)>;
+pub type ParseRule<'src> = (ParseFn<'src>, ParseFn<'src>, Precedence);
 
 pub type Precedence = u8;
 pub struct Precs;
+#[allow(dead_code)]
 impl Precs {
     pub const NONE: Precedence = 0;
     pub const ASSIGN: Precedence = 1;   // =
-    pub const BOOL_OR: Precedence = 2;  // ||
-    pub const BOOL_AND: Precedence = 3; // &&
+    pub const BOOL_OR: Precedence = 2;  // or
+    pub const BOOL_AND: Precedence = 3; // and
     pub const EQUALITY: Precedence = 4; // == !=
     pub const COMPARE: Precedence = 5;  // < <= > >=
     pub const TERM: Precedence = 6;     // + -
-    pub const FACTOR: Precedence = 7;   // * /
+    pub const FACTOR: Precedence = 7;   // * / %
     pub const UNARY: Precedence = 8;    // - !
-    pub const CALL: Precedence = 9;     // () .
+    pub const CALL: Precedence = 9;     // () [] .
     pub const PRIMARY: Precedence = 10; // Literals
 }
 
-impl<'src, 'chk> Parser<'src, 'chk> {
-    pub(super) fn get_rule(kind: TokenType) -> ParseRule<'src, 'chk> {
+impl<'src> Parser<'src> {
+    pub(super) fn get_rule(kind: TokenType) -> ParseRule<'src> {
         match kind {
             LeftParen => (
                 Some(Self::grouping),
-                None,
-                Precs::NONE
+                Some(Self::call),
+                Precs::CALL
             ),
             Minus => (
                 Some(Self::unary),
@@ -50,6 +51,11 @@ impl<'src, 'chk> Parser<'src, 'chk> {
                 Some(Self::binary),
                 Precs::FACTOR
             ),
+            Percent => (
+                None,
+                Some(Self::binary),
+                Precs::FACTOR
+            ),
             Number => (
                 Some(Self::number),
                 None,
@@ -110,6 +116,41 @@ impl<'src, 'chk> Parser<'src, 'chk> {
                 None,
                 Precs::NONE,
             ),
+            Identifier => (
+                Some(Self::variable),
+                None,
+                Precs::NONE,
+            ),
+            And => (
+                None,
+                Some(Self::and),
+                Precs::BOOL_AND,
+            ),
+            Or => (
+                None,
+                Some(Self::or),
+                Precs::BOOL_OR,
+            ),
+            LeftBracket => (
+                Some(Self::list),
+                Some(Self::index),
+                Precs::CALL,
+            ),
+            Dot => (
+                None,
+                Some(Self::dot),
+                Precs::CALL,
+            ),
+            This => (
+                Some(Self::this),
+                None,
+                Precs::NONE,
+            ),
+            Super => (
+                Some(Self::super_),
+                None,
+                Precs::NONE,
+            ),
             _ => (None, None, Precs::NONE)
         }
     }
@@ -0,0 +1,104 @@
+// Interactive prompt that runs Lox code as it is typed. All the
+// input runs in the same VM, so globals persist between lines.
+
+use std::env;
+use std::io::stdout;
+use std::path::PathBuf;
+use rustyline::DefaultEditor;
+use rustyline::error::ReadlineError;
+use rlox::{is_incomplete, LoxValue, Script, VM};
+use crate::report_error;
+
+const PROMPT: &str = "> ";
+const CONTINUATION_PROMPT: &str = "... ";
+const HISTORY_FILE: &str = ".rlox_history";
+const SOURCE_NAME: &str = "<repl>";
+
+const HELP: &str = "\
+Type Lox code to run it, the value of a trailing expression is printed.
+Input with unclosed parentheses, braces or strings continues on the next line.
+
+Commands:
+  :dis    Disassemble the last input that compiled
+  :help   Show this message
+  :quit   Exit the REPL (also Ctrl-D)
+
+Ctrl-C discards the current input.";
+
+/** Runs the REPL on the given VM until the user exits it */
+pub fn run(mut vm: VM) -> rustyline::Result<()> {
+    let mut editor = DefaultEditor::new()?;
+    let history = history_path();
+    if let Some(path) = &history {
+        // There's no history the first time the REPL is used
+        let _ = editor.load_history(path);
+    }
+
+    println!("rlox {} (type :help for help)", env!("CARGO_PKG_VERSION"));
+
+    let mut input = String::new();
+    let mut last_script: Option<Script> = None;
+
+    loop {
+        let prompt = if input.is_empty() { PROMPT } else { CONTINUATION_PROMPT };
+        let line = match editor.readline(prompt) {
+            Ok(line) => line,
+            Err(ReadlineError::Interrupted) => {
+                input.clear();
+                continue;
+            },
+            Err(ReadlineError::Eof) => break,
+            Err(err) => return Err(err),
+        };
+
+        if input.is_empty() {
+            let trimmed = line.trim();
+            if trimmed.is_empty() { continue }
+
+            if trimmed.starts_with(':') {
+                editor.add_history_entry(trimmed)?;
+                match trimmed {
+                    ":quit" | ":q" => break,
+                    ":help" => println!("{HELP}"),
+                    ":dis" => match &last_script {
+                        Some(script) => script.disassemble(&mut stdout())?,
+                        None => eprintln!("Nothing to disassemble yet."),
+                    },
+                    _ => eprintln!("Unknown command '{trimmed}', type :help for a list of commands."),
+                }
+                continue;
+            }
+        }
+
+        input.push_str(&line);
+        input.push('\n');
+        if is_incomplete(&input) { continue }
+
+        editor.add_history_entry(input.trim_end())?;
+        match vm.compile_eval(&input) {
+            Ok(script) => {
+                match vm.run_script(&script) {
+                    // Statements and calls that return nothing aren't echoed
+                    Ok(LoxValue::Null) => {},
+                    Ok(value) => println!("{value:?}"),
+                    Err(err) => report_error(&err, &input, SOURCE_NAME),
+                }
+                last_script = Some(script);
+            },
+            Err(err) => report_error(&err, &input, SOURCE_NAME),
+        }
+
+        input.clear();
+    }
+
+    if let Some(path) = &history {
+        editor.save_history(path)?;
+    }
+
+    Ok(())
+}
+
+/** The history is kept in the home directory, if there is one */
+fn history_path() -> Option<PathBuf> {
+    env::var_os("HOME").map(|home| PathBuf::from(home).join(HISTORY_FILE))
+}
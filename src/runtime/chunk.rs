@@ -1,26 +1,89 @@
+use std::collections::HashMap;
 use std::ops::Index;
-use crate::values::LoxValue;
+use std::rc::Rc;
+use crate::values::{LoxObject, LoxString, LoxValue};
+
+/// Max. number of position changes in each block of a line table
+const LINE_BLOCK_LEN: usize = 32;
 
 #[derive(Default)]
 pub struct Chunk {
     pub bytes: Vec<u8>,
-    pub lines: Vec<u32>,
     pub values: Vec<LoxValue>,
+    lines: LineTable,
+    // Position of the numbers and strings in `values`, so that each one is only stored once
+    constants: HashMap<ConstantKey, usize>,
+}
+
+/// Position in the source code that some bytecode was compiled from
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Position {
+    pub line: u32,
+    // 1-based, in characters
+    pub column: u32,
+}
+
+/// Maps the offset of each byte of a chunk to its position in the source code. Only
+/// the offsets where the position changes are stored, as variable-length deltas from
+/// the previous change, so most changes take one byte each for the offset, the line
+/// and the column. The deltas are split into blocks that can be decoded on their own,
+/// so looking up an offset only has to go through the block that contains it.
+#[derive(Default)]
+struct LineTable {
+    deltas: Vec<u8>,
+    // Offset of the first change in each block, and where the block starts in `deltas`
+    blocks: Vec<(u32, u32)>,
+    // Last change added, and the number of changes in its block
+    last: (u32, Position),
+    block_len: usize,
+}
+
+#[derive(PartialEq, Eq, Hash)]
+enum ConstantKey {
+    // Numbers are compared by their bits, so that 0 and -0 are different constants
+    Number(u64),
+    String(Rc<LoxString>),
 }
 
 impl Chunk {
     pub fn new() -> Self {
-        Self { bytes: vec![], lines: vec![], values: vec![] }
+        Self { bytes: vec![], values: vec![], lines: LineTable::default(), constants: HashMap::new() }
     }
 
-    pub fn write_byte(&mut self, byte: u8, line: u32) {
+    pub fn write_byte(&mut self, byte: u8, position: Position) {
+        self.lines.push(self.bytes.len(), position);
         self.bytes.push(byte);
-        self.lines.push(line);
     }
 
+    /** Returns the position in the source code of the byte at the given offset */
+    pub fn position(&self, offset: usize) -> Position {
+        self.lines.get(offset)
+    }
+
+    /** Returns the line of the byte at the given offset */
+    pub fn line(&self, offset: usize) -> u32 {
+        self.lines.get(offset).line
+    }
+
+    /** Adds a value to the constants table, returning its index. Numbers and
+    strings that are already in the table reuse the existing constant. */
     pub fn add_constant(&mut self, value: LoxValue) -> usize {
+        let key = match &value {
+            LoxValue::Number(n) => Some(ConstantKey::Number(n.to_bits())),
+            LoxValue::Object(LoxObject::String(s)) => Some(ConstantKey::String(s.clone())),
+            _ => None,
+        };
+
+        if let Some(&ix) = key.as_ref().and_then(|key| self.constants.get(key)) {
+            return ix;
+        }
+
         self.values.push(value);
-        self.values.len() - 1
+        let ix = self.values.len() - 1;
+        if let Some(key) = key {
+            self.constants.insert(key, ix);
+        }
+        ix
     }
 
     #[allow(clippy::len_without_is_empty)]
@@ -29,6 +92,83 @@ impl Chunk {
     }
 }
 
+impl LineTable {
+    fn push(&mut self, offset: usize, position: Position) {
+        if !self.blocks.is_empty() && self.last.1 == position {
+            return;
+        }
+
+        // The first change of a block is relative to offset 0, at line 0 and column 0
+        if self.blocks.is_empty() || self.block_len == LINE_BLOCK_LEN {
+            self.blocks.push((offset as u32, self.deltas.len() as u32));
+            self.last = (0, Position::default());
+            self.block_len = 0;
+        }
+
+        let (last_offset, last) = self.last;
+        write_varint(&mut self.deltas, offset as u32 - last_offset);
+        write_varint(&mut self.deltas, zigzag(position.line.wrapping_sub(last.line)));
+        write_varint(&mut self.deltas, zigzag(position.column.wrapping_sub(last.column)));
+        self.last = (offset as u32, position);
+        self.block_len += 1;
+    }
+
+    fn get(&self, offset: usize) -> Position {
+        // The block that contains the offset is the last one starting at or before it
+        let block = self.blocks.partition_point(|&(start, _)| start as usize <= offset) - 1;
+        let start = self.blocks[block].1 as usize;
+        let end = self.blocks.get(block + 1).map_or(self.deltas.len(), |&(_, ix)| ix as usize);
+
+        let mut deltas = &self.deltas[start .. end];
+        let (mut current, mut position) = (0, Position::default());
+        while !deltas.is_empty() {
+            current += read_varint(&mut deltas);
+            if current as usize > offset {
+                break;
+            }
+
+            position.line = position.line.wrapping_add(unzigzag(read_varint(&mut deltas)));
+            position.column = position.column.wrapping_add(unzigzag(read_varint(&mut deltas)));
+        }
+
+        position
+    }
+}
+
+/** Appends a number using as few bytes as possible, 7 bits
+per byte with the highest bit set in all bytes but the last */
+fn write_varint(bytes: &mut Vec<u8>, mut n: u32) {
+    while n >= 0x80 {
+        bytes.push(n as u8 | 0x80);
+        n >>= 7;
+    }
+    bytes.push(n as u8);
+}
+
+/** Reads a number written by `write_varint`, advancing past it */
+fn read_varint(bytes: &mut &[u8]) -> u32 {
+    let mut n = 0;
+    for (i, &byte) in bytes.iter().enumerate() {
+        n |= ((byte & 0x7f) as u32) << (7 * i);
+        if byte < 0x80 {
+            *bytes = &bytes[i + 1 ..];
+            break;
+        }
+    }
+    n
+}
+
+/** Maps a difference that may be negative to an unsigned number that stays
+small when the difference is small: 0, -1, 1, -2, 2... become 0, 1, 2, 3, 4... */
+fn zigzag(diff: u32) -> u32 {
+    let diff = diff as i32;
+    ((diff << 1) ^ (diff >> 31)) as u32
+}
+
+fn unzigzag(n: u32) -> u32 {
+    (n >> 1) ^ (n & 1).wrapping_neg()
+}
+
 impl Index<usize> for Chunk {
     type Output = u8;
 
@@ -43,4 +183,47 @@ impl Index<u8> for Chunk {
     fn index(&self, index: u8) -> &Self::Output {
         &self.bytes[index as usize]
     }
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use crate::parsing::Parser;
+    use crate::values::StringTable;
+    use super::*;
+
+    fn position(line: u32, column: u32) -> Position {
+        Position { line, column }
+    }
+
+    #[test]
+    fn line_table_finds_every_offset() {
+        // Positions may go back, like operators that are emitted after their operands,
+        // and there are more changes than fit in one block, some of them far apart
+        let mut expected = vec![];
+        for i in 0 .. 5 * LINE_BLOCK_LEN as u32 {
+            let position = match i % 4 {
+                0 => position(i / 3 + 1, 1),
+                1 => position(i / 3 + 1, 300 + i),
+                2 => position(i / 3 + 1, 2),
+                _ => position(i, 100_000),
+            };
+            expected.extend(std::iter::repeat_n(position, 1 + (i % 3) as usize));
+        }
+
+        let mut chunk = Chunk::new();
+        for &position in &expected {
+            chunk.write_byte(0, position);
+        }
+
+        for (offset, &position) in expected.iter().enumerate() {
+            assert_eq!(chunk.position(offset), position, "at offset {offset}");
+        }
+    }
+
+    #[test]
+    fn line_table_is_smaller_than_one_line_per_byte() {
+        let function = Parser::new("let a = b + c * 2; print(a);", &mut StringTable::new()).compile().ok().unwrap();
+        let chunk = &function.chunk;
+        let table_size = chunk.lines.deltas.len() + chunk.lines.blocks.len() * size_of::<(u32, u32)>();
+        assert!(table_size < chunk.len() * size_of::<u32>(), "{table_size} bytes for {} bytes of code", chunk.len());
+    }
+}
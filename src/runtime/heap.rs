@@ -0,0 +1,363 @@
+//! Garbage collection of the objects that can form reference cycles.
+//!
+//! Objects are reference counted, so most of them are freed as soon as the last
+//! handle to them goes away. Reference counting can't free cycles though, like a
+//! closure that captures itself or two instances that point to each other. The
+//! heap keeps track of every object that can be part of a cycle, and a mark-and-sweep
+//! collection breaks the cycles that can't be reached anymore by clearing their contents.
+//!
+//! Strings, functions and natives can't point back to any of these objects, so they
+//! are left to reference counting alone. That includes everything the compiler creates,
+//! which is why the compiler doesn't need to be a root.
+
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet};
+use std::rc::{Rc, Weak};
+use crate::values::{
+    LoxValue, LoxObject, LoxClosure, LoxUpvalue, UpvalueRef,
+    LoxClass, LoxInstance, LoxBoundMethod, LoxList,
+};
+
+/// Number of tracked objects that triggers the first collection
+const INITIAL_THRESHOLD: usize = 1024;
+/// After a collection, the next one happens once the heap grows by this factor
+const GROWTH_FACTOR: usize = 2;
+
+pub struct Heap {
+    // Tracked objects by address. Objects that reference counting frees
+    // stay here until the next collection, which removes them.
+    objects: HashMap<usize, Tracked>,
+    next_gc: usize,
+}
+
+/// Weak handle to an object that can be part of a reference cycle
+enum Tracked {
+    Closure(Weak<LoxClosure>),
+    Upvalue(Weak<RefCell<LoxUpvalue>>),
+    Class(Weak<LoxClass>),
+    Instance(Weak<LoxInstance>),
+    BoundMethod(Weak<LoxBoundMethod>),
+    List(Weak<LoxList>),
+}
+
+/// Strong handle to a tracked object, used while collecting
+#[derive(Clone)]
+pub enum Object {
+    Closure(Rc<LoxClosure>),
+    Upvalue(UpvalueRef),
+    Class(Rc<LoxClass>),
+    Instance(Rc<LoxInstance>),
+    BoundMethod(Rc<LoxBoundMethod>),
+    List(Rc<LoxList>),
+}
+
+/// Marks the objects that are reachable from the roots
+pub struct Marker {
+    marked: HashSet<usize>,
+    // Marked objects whose references haven't been marked yet
+    gray: Vec<Object>,
+}
+
+impl Heap {
+    pub fn new() -> Self {
+        Self { objects: HashMap::new(), next_gc: INITIAL_THRESHOLD }
+    }
+
+    pub fn track(&mut self, object: &Object) {
+        self.objects.insert(object.address(), object.downgrade());
+    }
+
+    /** Checks whether the heap has grown enough to collect garbage before the next allocation */
+    pub fn should_collect(&self) -> bool {
+        cfg!(feature = "gc-stress") || self.objects.len() >= self.next_gc
+    }
+
+    /** Breaks the cycles of the objects that can't be reached from the roots marked by the given function */
+    pub fn collect(&mut self, mark_roots: impl FnOnce(&mut Marker)) {
+        let live: Vec<Object> = self.objects.values().filter_map(Tracked::upgrade).collect();
+
+        let mut marker = Marker { marked: HashSet::new(), gray: vec![] };
+        mark_roots(&mut marker);
+        marker.trace();
+
+        // Rust code may be holding objects that aren't reachable from the roots,
+        // like natives or embedders. Those objects have more handles than the ones
+        // coming from other unreachable objects, so they are roots too.
+        let mut internal_refs: HashMap<usize, usize> = HashMap::new();
+        for object in live.iter().filter(|obj| !marker.is_marked(obj)) {
+            for child in object.references() {
+                *internal_refs.entry(child.address()).or_default() += 1;
+            }
+        }
+
+        for object in &live {
+            // One of the handles is the one in `live`
+            let handles = object.strong_count() - 1;
+            if !marker.is_marked(object) && handles > internal_refs.get(&object.address()).copied().unwrap_or(0) {
+                marker.mark(object.clone());
+                marker.trace();
+            }
+        }
+
+        // Sweep: clearing the unreachable objects breaks their cycles,
+        // so they are freed once the handles in `live` are dropped
+        for object in live.iter().filter(|obj| !marker.is_marked(obj)) {
+            object.clear();
+        }
+
+        drop(live);
+        self.objects.retain(|_, tracked| tracked.is_alive());
+        self.next_gc = (self.objects.len() * GROWTH_FACTOR).max(INITIAL_THRESHOLD);
+    }
+}
+
+impl Marker {
+    pub fn mark_value(&mut self, value: &LoxValue) {
+        if let Some(object) = Object::from_value(value) {
+            self.mark(object);
+        }
+    }
+
+    pub fn mark(&mut self, object: Object) {
+        if self.marked.insert(object.address()) {
+            self.gray.push(object);
+        }
+    }
+
+    fn is_marked(&self, object: &Object) -> bool {
+        self.marked.contains(&object.address())
+    }
+
+    /** Marks everything reachable from the objects marked so far */
+    fn trace(&mut self) {
+        while let Some(object) = self.gray.pop() {
+            for child in object.references() {
+                self.mark(child);
+            }
+        }
+    }
+}
+
+impl Object {
+    pub fn from_value(value: &LoxValue) -> Option<Self> {
+        match value {
+            LoxValue::Object(LoxObject::Closure(closure)) => Some(Self::Closure(closure.clone())),
+            LoxValue::Object(LoxObject::Class(class)) => Some(Self::Class(class.clone())),
+            LoxValue::Object(LoxObject::Instance(instance)) => Some(Self::Instance(instance.clone())),
+            LoxValue::Object(LoxObject::BoundMethod(bound)) => Some(Self::BoundMethod(bound.clone())),
+            LoxValue::Object(LoxObject::List(list)) => Some(Self::List(list.clone())),
+            _ => None,
+        }
+    }
+
+    fn address(&self) -> usize {
+        match self {
+            Self::Closure(rc) => Rc::as_ptr(rc) as *const () as usize,
+            Self::Upvalue(rc) => Rc::as_ptr(rc) as *const () as usize,
+            Self::Class(rc) => Rc::as_ptr(rc) as *const () as usize,
+            Self::Instance(rc) => Rc::as_ptr(rc) as *const () as usize,
+            Self::BoundMethod(rc) => Rc::as_ptr(rc) as *const () as usize,
+            Self::List(rc) => Rc::as_ptr(rc) as *const () as usize,
+        }
+    }
+
+    fn strong_count(&self) -> usize {
+        match self {
+            Self::Closure(rc) => Rc::strong_count(rc),
+            Self::Upvalue(rc) => Rc::strong_count(rc),
+            Self::Class(rc) => Rc::strong_count(rc),
+            Self::Instance(rc) => Rc::strong_count(rc),
+            Self::BoundMethod(rc) => Rc::strong_count(rc),
+            Self::List(rc) => Rc::strong_count(rc),
+        }
+    }
+
+    fn downgrade(&self) -> Tracked {
+        match self {
+            Self::Closure(rc) => Tracked::Closure(Rc::downgrade(rc)),
+            Self::Upvalue(rc) => Tracked::Upvalue(Rc::downgrade(rc)),
+            Self::Class(rc) => Tracked::Class(Rc::downgrade(rc)),
+            Self::Instance(rc) => Tracked::Instance(Rc::downgrade(rc)),
+            Self::BoundMethod(rc) => Tracked::BoundMethod(Rc::downgrade(rc)),
+            Self::List(rc) => Tracked::List(Rc::downgrade(rc)),
+        }
+    }
+
+    /** Returns the tracked objects this one points to */
+    fn references(&self) -> Vec<Object> {
+        match self {
+            Self::Closure(closure) => closure.upvalues.iter().cloned().map(Self::Upvalue).collect(),
+            Self::Upvalue(upvalue) => match &*upvalue.borrow() {
+                LoxUpvalue::Closed(value) => Self::from_value(value).into_iter().collect(),
+                // Open upvalues point to the stack, which is a root already
+                LoxUpvalue::Open(_) => vec![],
+            },
+            Self::Class(class) => class.methods.borrow().values().cloned().map(Self::Closure).collect(),
+            Self::Instance(instance) => {
+                let mut refs = objects_in(instance.fields.borrow().values());
+                refs.push(Self::Class(instance.class.clone()));
+                refs
+            },
+            Self::BoundMethod(bound) => {
+                let mut refs = objects_in([&bound.receiver]);
+                refs.push(Self::Closure(bound.method.clone()));
+                refs
+            },
+            Self::List(list) => objects_in(list.items.borrow().iter()),
+        }
+    }
+
+    /** Drops the references held by the object. Every cycle goes through
+    at least one of the objects whose contents can be changed. */
+    fn clear(&self) {
+        match self {
+            Self::Upvalue(upvalue) => *upvalue.borrow_mut() = LoxUpvalue::Closed(LoxValue::Null),
+            Self::Class(class) => class.methods.borrow_mut().clear(),
+            Self::Instance(instance) => instance.fields.borrow_mut().clear(),
+            Self::List(list) => list.items.borrow_mut().clear(),
+            Self::Closure(_) | Self::BoundMethod(_) => {},
+        }
+    }
+}
+
+impl Tracked {
+    fn upgrade(&self) -> Option<Object> {
+        match self {
+            Self::Closure(weak) => weak.upgrade().map(Object::Closure),
+            Self::Upvalue(weak) => weak.upgrade().map(Object::Upvalue),
+            Self::Class(weak) => weak.upgrade().map(Object::Class),
+            Self::Instance(weak) => weak.upgrade().map(Object::Instance),
+            Self::BoundMethod(weak) => weak.upgrade().map(Object::BoundMethod),
+            Self::List(weak) => weak.upgrade().map(Object::List),
+        }
+    }
+
+    fn is_alive(&self) -> bool {
+        match self {
+            Self::Closure(weak) => weak.strong_count() > 0,
+            Self::Upvalue(weak) => weak.strong_count() > 0,
+            Self::Class(weak) => weak.strong_count() > 0,
+            Self::Instance(weak) => weak.strong_count() > 0,
+            Self::BoundMethod(weak) => weak.strong_count() > 0,
+            Self::List(weak) => weak.strong_count() > 0,
+        }
+    }
+}
+
+fn objects_in<'a>(values: impl IntoIterator<Item = &'a LoxValue>) -> Vec<Object> {
+    values.into_iter().filter_map(Object::from_value).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::errors::NativeError;
+    use crate::runtime::VM;
+    use super::*;
+
+    // These are most useful with the `gc-stress` feature, which
+    // collects garbage every time an object is created
+
+    fn tracked(value: &LoxValue) -> Tracked {
+        Object::from_value(value).unwrap().downgrade()
+    }
+
+    fn items(value: &LoxValue) -> Vec<LoxValue> {
+        match value {
+            LoxValue::Object(LoxObject::List(list)) => list.items.borrow().clone(),
+            _ => panic!("Expected a list, got {value}"),
+        }
+    }
+
+    fn field(value: &LoxValue, name: &str) -> LoxValue {
+        match value {
+            LoxValue::Object(LoxObject::Instance(instance)) => {
+                instance.fields.borrow().iter()
+                    .find(|(key, _)| key.as_str() == name)
+                    .map(|(_, value)| value.clone())
+                    .unwrap()
+            },
+            _ => panic!("Expected an instance, got {value}"),
+        }
+    }
+
+    #[test]
+    fn unreachable_cycles_are_freed() {
+        let mut vm = VM::new();
+        let objects = vm.eval("
+            let list = [0];
+            list[0] = list;
+
+            class A {}
+            let instance = A();
+            instance.me = instance;
+
+            fn make() {
+                fn f() { return f; }
+                return f;
+            }
+            let closure = make();
+
+            [list, instance, closure]
+        ").unwrap();
+
+        let tracked: Vec<Tracked> = items(&objects).iter().map(tracked).collect();
+        drop(objects);
+        vm.interpret("list = null; instance = null; closure = null;").unwrap();
+        assert!(tracked.iter().all(Tracked::is_alive));
+
+        vm.collect_garbage();
+        assert!(!tracked.iter().any(Tracked::is_alive));
+    }
+
+    #[test]
+    fn objects_held_by_rust_survive() {
+        let mut vm = VM::new();
+        let instance = vm.eval("
+            class A {}
+            let a = A();
+            a.me = a;
+            a.items = [1, 2];
+            a
+        ").unwrap();
+
+        vm.interpret("
+            a = null;
+            for (let i = 0; i < 100; i += 1) {
+                let garbage = [i];
+                garbage[0] = garbage;
+            }
+        ").unwrap();
+        vm.collect_garbage();
+
+        assert_eq!(field(&instance, "me"), instance);
+        assert_eq!(items(&field(&instance, "items")), [LoxValue::Number(1.0), LoxValue::Number(2.0)]);
+    }
+
+    #[test]
+    fn objects_held_by_natives_survive() {
+        // The list only exists in the native while the Lox code called by it runs
+        fn hold(vm: &mut VM, _args: &[LoxValue]) -> Result<LoxValue, NativeError> {
+            let list = vm.call_function("make", &[])?;
+            vm.call_function("churn", &[])?;
+            Ok(list)
+        }
+
+        let mut vm = VM::new();
+        vm.define_native("hold", 0, hold);
+        let list = vm.eval("
+            fn make() {
+                let list = [1, [2]];
+                list[1][0] = list;
+                return list;
+            }
+            fn churn() {
+                for (let i = 0; i < 100; i += 1) [i, [i]];
+            }
+            hold()
+        ").unwrap();
+
+        let list_items = items(&list);
+        assert_eq!(list_items[0], LoxValue::Number(1.0));
+        assert_eq!(items(&list_items[1]), [list]);
+    }
+}
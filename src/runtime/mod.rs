@@ -1,7 +1,12 @@
 mod chunk;
+mod heap;
+mod natives;
 mod opcodes;
+mod tracer;
 mod vm;
 
-pub use chunk::Chunk;
+pub use chunk::{Chunk, Position};
+#[allow(unused_imports)]
 pub use opcodes::{OpCode, OpCodes};
-pub use vm::{InterpretResult, VM};
\ No newline at end of file
+pub use tracer::{TraceFormat, Tracer};
+pub use vm::{Script, VM};
\ No newline at end of file
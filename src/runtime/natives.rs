@@ -0,0 +1,59 @@
+//! Built-in functions implemented in Rust and available to all Lox programs.
+
+use std::io::stdin;
+use std::time::{SystemTime, UNIX_EPOCH};
+use crate::errors::NativeError;
+use crate::runtime::VM;
+use crate::values::{LoxValue, LoxObject};
+
+impl VM {
+    pub(super) fn define_builtin_natives(&mut self) {
+        self.define_native("clock", 0, clock);
+        self.define_native("print", 1, print);
+        self.define_native("input", 0, input);
+        self.define_native("len", 1, len);
+    }
+}
+
+/** Returns the number of seconds since the Unix epoch, useful for timing code */
+fn clock(_vm: &mut VM, _args: &[LoxValue]) -> Result<LoxValue, NativeError> {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_err(|_| "System clock is set before the Unix epoch.".to_owned())?;
+
+    Ok(LoxValue::Number(now.as_secs_f64()))
+}
+
+/** Prints a value to the output of the VM, followed by a newline */
+fn print(vm: &mut VM, args: &[LoxValue]) -> Result<LoxValue, NativeError> {
+    writeln!(vm.output(), "{}", args[0])
+        .map_err(|err| format!("Could not write the output: {err}"))?;
+    Ok(LoxValue::Null)
+}
+
+/** Reads a line from the standard input without the trailing newline,
+or returns null if there's nothing left to read */
+fn input(_vm: &mut VM, _args: &[LoxValue]) -> Result<LoxValue, NativeError> {
+    let mut line = String::new();
+    let read = stdin().read_line(&mut line)
+        .map_err(|err| format!("Could not read from the standard input: {err}"))?;
+
+    if read == 0 {
+        return Ok(LoxValue::Null);
+    }
+
+    let trimmed = line.trim_end_matches(['\n', '\r']).len();
+    line.truncate(trimmed);
+    Ok(LoxValue::from(line))
+}
+
+/** Returns the number of items in a list or characters in a string */
+fn len(_vm: &mut VM, args: &[LoxValue]) -> Result<LoxValue, NativeError> {
+    let len = match &args[0] {
+        LoxValue::Object(LoxObject::List(list)) => list.items.borrow().len(),
+        LoxValue::Object(LoxObject::String(s)) => s.chars().count(),
+        _ => return Err("Only lists and strings have a length.".into()),
+    };
+
+    Ok(LoxValue::Number(len as f64))
+}
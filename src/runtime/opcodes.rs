@@ -16,4 +16,63 @@ impl OpCodes {
     pub const OP_EQUAL: OpCode = 11;
     pub const OP_GREATER: OpCode = 12;
     pub const OP_LESS: OpCode = 13;
+    pub const OP_POP: OpCode = 14;
+    pub const OP_DEFINE_GLOBAL: OpCode = 15;
+    pub const OP_GET_GLOBAL: OpCode = 16;
+    pub const OP_SET_GLOBAL: OpCode = 17;
+    pub const OP_GET_LOCAL: OpCode = 18;
+    pub const OP_SET_LOCAL: OpCode = 19;
+    pub const OP_JUMP: OpCode = 20;
+    pub const OP_JUMP_IF_FALSE: OpCode = 21;
+    pub const OP_LOOP: OpCode = 22;
+    pub const OP_MODULO: OpCode = 23;
+    pub const OP_CALL: OpCode = 24;
+    pub const OP_CLOSURE: OpCode = 25;
+    pub const OP_GET_UPVALUE: OpCode = 26;
+    pub const OP_SET_UPVALUE: OpCode = 27;
+    pub const OP_CLOSE_UPVALUE: OpCode = 28;
+    pub const OP_CLASS: OpCode = 29;
+    pub const OP_GET_PROPERTY: OpCode = 30;
+    pub const OP_SET_PROPERTY: OpCode = 31;
+    pub const OP_METHOD: OpCode = 32;
+    pub const OP_INVOKE: OpCode = 33;
+    pub const OP_DUP: OpCode = 34;
+    pub const OP_INHERIT: OpCode = 35;
+    pub const OP_GET_SUPER: OpCode = 36;
+    pub const OP_SUPER_INVOKE: OpCode = 37;
+    pub const OP_LIST: OpCode = 38;
+    pub const OP_GET_INDEX: OpCode = 39;
+    pub const OP_SET_INDEX: OpCode = 40;
+    pub const OP_DUP2: OpCode = 41;
+    pub const OP_CONSTANT_LONG: OpCode = 42;
+    pub const OP_DEFINE_GLOBAL_LONG: OpCode = 43;
+    pub const OP_GET_GLOBAL_LONG: OpCode = 44;
+    pub const OP_SET_GLOBAL_LONG: OpCode = 45;
+    pub const OP_CLOSURE_LONG: OpCode = 46;
+    pub const OP_CLASS_LONG: OpCode = 47;
+    pub const OP_GET_PROPERTY_LONG: OpCode = 48;
+    pub const OP_SET_PROPERTY_LONG: OpCode = 49;
+    pub const OP_METHOD_LONG: OpCode = 50;
+    pub const OP_INVOKE_LONG: OpCode = 51;
+    pub const OP_GET_SUPER_LONG: OpCode = 52;
+    pub const OP_SUPER_INVOKE_LONG: OpCode = 53;
+
+    /** Returns the form of an instruction that takes a 3-byte constant index instead of a 1-byte one */
+    pub fn long_form(op: OpCode) -> OpCode {
+        match op {
+            Self::OP_CONSTANT => Self::OP_CONSTANT_LONG,
+            Self::OP_DEFINE_GLOBAL => Self::OP_DEFINE_GLOBAL_LONG,
+            Self::OP_GET_GLOBAL => Self::OP_GET_GLOBAL_LONG,
+            Self::OP_SET_GLOBAL => Self::OP_SET_GLOBAL_LONG,
+            Self::OP_CLOSURE => Self::OP_CLOSURE_LONG,
+            Self::OP_CLASS => Self::OP_CLASS_LONG,
+            Self::OP_GET_PROPERTY => Self::OP_GET_PROPERTY_LONG,
+            Self::OP_SET_PROPERTY => Self::OP_SET_PROPERTY_LONG,
+            Self::OP_METHOD => Self::OP_METHOD_LONG,
+            Self::OP_INVOKE => Self::OP_INVOKE_LONG,
+            Self::OP_GET_SUPER => Self::OP_GET_SUPER_LONG,
+            Self::OP_SUPER_INVOKE => Self::OP_SUPER_INVOKE_LONG,
+            _ => unreachable!("Instruction {op} doesn't take a constant index"),
+        }
+    }
 }
\ No newline at end of file
@@ -0,0 +1,117 @@
+//! Tracing of the instructions run by the VM, for debugging the VM itself.
+
+use std::io::{Result, Write};
+use std::ops::RangeInclusive;
+use crate::debug::{debug_instruction, next_instruction, opcode_name};
+use crate::values::{LoxFunction, Value};
+
+/// Name used in filters and traces for the top-level script
+const SCRIPT_NAME: &str = "script";
+
+/// Writes a record of every instruction run by a VM, along with the state of its stack
+pub struct Tracer {
+    output: Box<dyn Write>,
+    format: TraceFormat,
+    // Only instructions that match all of the filters are traced,
+    // an empty filter lets everything through
+    functions: Vec<String>,
+    lines: Vec<RangeInclusive<u32>>,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum TraceFormat {
+    /// The stack followed by the disassembled instruction, meant for humans
+    #[default]
+    Text,
+    /// One JSON object per instruction, with its `function`, `line`, `column`, `ip`,
+    /// `opcode`, `operands` and a `stack` snapshot, meant for tools
+    JsonLines,
+}
+
+impl Tracer {
+    pub fn new(output: Box<dyn Write>) -> Self {
+        Self { output, format: TraceFormat::Text, functions: vec![], lines: vec![] }
+    }
+
+    pub fn format(mut self, format: TraceFormat) -> Self {
+        self.format = format;
+        self
+    }
+
+    /** Traces the instructions of the functions with the given name, use "script" for top-level code.
+    Can be called several times to trace more than one function. */
+    pub fn function(mut self, name: &str) -> Self {
+        self.functions.push(name.to_owned());
+        self
+    }
+
+    /** Traces the instructions compiled from the given lines of code.
+    Can be called several times to trace more than one range. */
+    pub fn lines(mut self, lines: RangeInclusive<u32>) -> Self {
+        self.lines.push(lines);
+        self
+    }
+
+    /** Records the instruction of the function at the given position, which is about to be run */
+    pub(super) fn trace(&mut self, function: &LoxFunction, ip: usize, stack: &[Value]) -> Result<()> {
+        let chunk = &function.chunk;
+        let position = chunk.position(ip);
+        let line = position.line;
+        let name = function.name.as_deref().unwrap_or(SCRIPT_NAME);
+
+        let function_matches = self.functions.is_empty() || self.functions.iter().any(|f| f == name);
+        let line_matches = self.lines.is_empty() || self.lines.iter().any(|range| range.contains(&line));
+        if !function_matches || !line_matches {
+            return Ok(());
+        }
+
+        match self.format {
+            TraceFormat::Text => {
+                write!(self.output, "[ ")?;
+                for val in stack {
+                    write!(self.output, "{val:?} ")?;
+                }
+                writeln!(self.output, "]")?;
+                debug_instruction(&mut self.output, chunk, ip)?;
+            },
+            TraceFormat::JsonLines => {
+                let operands = &chunk.bytes[ip + 1 .. next_instruction(chunk, ip)];
+                let operands: Vec<String> = operands.iter().map(u8::to_string).collect();
+                let stack: Vec<String> = stack.iter().map(|val| json_string(&format!("{val:?}"))).collect();
+
+                writeln!(
+                    self.output,
+                    r#"{{"function":{},"line":{line},"column":{},"ip":{ip},"opcode":"{}","operands":[{}],"stack":[{}]}}"#,
+                    json_string(name),
+                    position.column,
+                    opcode_name(chunk[ip]),
+                    operands.join(","),
+                    stack.join(","),
+                )?;
+            },
+        }
+
+        Ok(())
+    }
+}
+
+/** Quotes and escapes a string so that it can be used in JSON */
+fn json_string(s: &str) -> String {
+    let mut escaped = String::with_capacity(s.len() + 2);
+    escaped.push('"');
+
+    for c in s.chars() {
+        match c {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            '\n' => escaped.push_str("\\n"),
+            '\r' => escaped.push_str("\\r"),
+            '\t' => escaped.push_str("\\t"),
+            c if c.is_control() => escaped.push_str(&format!("\\u{:04x}", c as u32)),
+            c => escaped.push(c),
+        }
+    }
+
+    escaped.push('"');
+    escaped
+}
@@ -1,195 +1,925 @@
-use std::mem::take;
+// Values in the stack are converted to and from `LoxValue`, which does nothing
+// when the `nan-boxing` feature is disabled and both types are the same
+#![allow(clippy::useless_conversion)]
+
+use std::cell::RefCell;
+use std::io::{stdout, Write};
+use std::mem::replace;
+use std::rc::Rc;
+use crate::debug::disassemble_function;
+use crate::errors::{InterpretResult, LoxError, NativeError, RuntimeError, TraceFrame};
 use crate::parsing::Parser;
-use crate::runtime::{Chunk, OpCodes};
-use crate::values::LoxValue;
+use crate::runtime::{Chunk, OpCodes, Tracer};
+use crate::runtime::heap::{Heap, Object};
+use crate::values::{
+    LoxValue, LoxObject, LoxFunction, LoxClosure, LoxUpvalue, UpvalueRef,
+    LoxClass, LoxInstance, LoxBoundMethod, LoxNative, NativeFn, LoxList,
+    LoxString, StringMap, StringTable, Value,
+};
 
-const STACK_SIZE: usize = 256;
+/// Default max. number of nested calls before raising a stack overflow error
+const DEFAULT_MAX_FRAMES: usize = 10_000;
+/// Max. number of nested runs of the VM, which happen when natives call Lox functions.
+/// Each of them uses some of the Rust stack, so they are limited separately from frames.
+const MAX_NESTED_RUNS: usize = 200;
+/// Initial capacity of the stack, enough for 64 frames with all their locals
+const STACK_SIZE: usize = 64 * 256;
+/// Name of the method that is called when creating an instance of a class
+const INITIALIZER_NAME: &str = "init";
 
 pub struct VM {
-    ip: usize,
-    stack: [LoxValue; STACK_SIZE],
-    stack_top: usize,
+    // The most common instructions work on these values directly, the rest
+    // convert them from and to `LoxValue` as they're popped and pushed
+    stack: Vec<Value>,
+    frames: Vec<CallFrame>,
+    max_frames: usize,
+    // Number of calls from Rust that are currently running
+    nested_runs: usize,
+    // Frames below this one belong to the code that called into the current
+    // run from Rust, so they're left out of the traces of its errors
+    frame_base: usize,
+    globals: StringMap<LoxValue>,
+    // Every string used by the programs run by this VM
+    strings: StringTable,
+    // Interned name of the initializer, to look it up without hashing it every time
+    init_string: Rc<LoxString>,
+    // Upvalues pointing to variables that are still on the stack,
+    // sorted by their stack slot
+    open_upvalues: Vec<UpvalueRef>,
+    // Objects that can be part of reference cycles
+    heap: Heap,
+    // Where the output of `print` goes
+    output: Box<dyn Write>,
+    // Records every instruction before running it, if enabled
+    tracer: Option<Tracer>,
 }
 
-#[derive(Copy, Clone, Debug)]
-pub enum InterpretResult {
-    Ok,
-    CompileError,
-    RuntimeError,
+/// A compiled Lox program, ready to be run by a VM
+pub struct Script {
+    function: Rc<LoxFunction>,
+}
+
+impl Script {
+    /** Writes a human-readable listing of the bytecode of the script and its functions */
+    pub fn disassemble(&self, output: &mut dyn Write) -> std::io::Result<()> {
+        disassemble_function(output, &self.function)
+    }
+}
+
+/// An ongoing function call
+struct CallFrame {
+    closure: Rc<LoxClosure>,
+    ip: usize,
+    // Index of the first stack slot that belongs to this frame,
+    // which holds the function being called
+    slots: usize,
 }
 
 macro_rules! binary_op {
     // Macro for arithmetic operations in the VM that take two values.
     // It is generic over the arithmetic operator to use.
-    ($self:ident, $chunk:ident, $op:tt) => {
+    ($self:ident, $op:tt) => {
         {
-            if let Some(lox_val) = $self.pop() $op $self.pop() {
+            // Numbers are operated on in place, which avoids unpacking and packing them again
+            let len = $self.stack.len();
+            if let (Some(a), Some(b)) = ($self.stack[len - 2].as_number(), $self.stack[len - 1].as_number()) {
+                $self.stack.truncate(len - 1);
+                $self.stack[len - 2] = Value::from(a $op b);
+            } else if let Some(lox_val) = $self.pop() $op $self.pop() {
                 $self.push(lox_val);
             } else {
-                runtime_error!($self, $chunk, "Values have incompatible types.");
+                runtime_error!($self, "Values have incompatible types.");
             }
         }
     };
 }
 
-macro_rules! runtime_error {
-    ($self:ident, $chunk:ident, $($args:expr),+) => {
+macro_rules! comparison_op {
+    // Macro for comparisons between two numbers, generic over the operator
+    ($self:ident, $op:tt) => {
         {
-            eprintln!($($args),+);
-            let line_err = $chunk.lines[$self.ip - 1];
-            eprintln!("[line {line_err}] in script.");
-            $self.reset_stack();
-            return InterpretResult::RuntimeError;
+            let b = $self.pop_value();
+            let a = $self.pop_value();
+            match (a.as_number(), b.as_number()) {
+                (Some(a), Some(b)) => $self.stack.push(Value::from(a $op b)),
+                _ => runtime_error!($self, "Values must be numbers."),
+            }
         }
     };
 }
 
+macro_rules! runtime_error {
+    ($self:ident, $($args:expr),+) => {
+        return Err($self.runtime_error(format!($($args),+)))
+    };
+}
+
 impl VM {
     #[allow(clippy::new_without_default)]
     pub fn new() -> Self {
-        Self {
-            ip: 0,
-            stack: std::array::from_fn(|_| LoxValue::default()),
-            stack_top: 0
-        }
+        let mut strings = StringTable::new();
+        let init_string = strings.intern(INITIALIZER_NAME);
+
+        let mut vm = Self {
+            stack: Vec::with_capacity(STACK_SIZE),
+            frames: Vec::with_capacity(64),
+            max_frames: DEFAULT_MAX_FRAMES,
+            nested_runs: 0,
+            frame_base: 0,
+            globals: StringMap::default(),
+            strings,
+            init_string,
+            open_upvalues: vec![],
+            heap: Heap::new(),
+            output: Box::new(stdout()),
+            tracer: None,
+        };
+
+        vm.define_builtin_natives();
+        vm
     }
 
+    /** Exposes a Rust function to Lox code as a global with the given name */
+    pub fn define_native(&mut self, name: &str, arity: u8, function: NativeFn) {
+        let native = Rc::new(LoxNative::new(name, arity, function));
+        let name = self.strings.intern(name);
+        self.globals.insert(name, LoxValue::Object(LoxObject::Native(native)));
+    }
+
+    /** Sets the max. number of nested function calls, deeper calls result in a stack overflow */
+    pub fn set_max_call_depth(&mut self, depth: usize) {
+        self.max_frames = depth;
+    }
+
+    /** Redirects the output of the programs run by this VM, which goes to stdout by default */
+    pub fn set_output(&mut self, output: Box<dyn Write>) {
+        self.output = output;
+    }
+
+    /** Returns the writer where the output of the programs run by this VM goes */
+    pub fn output(&mut self) -> &mut dyn Write {
+        &mut self.output
+    }
+
+    /** Traces every instruction run by the VM with the given tracer, or stops tracing with None */
+    pub fn set_tracer(&mut self, tracer: Option<Tracer>) {
+        self.tracer = tracer;
+    }
+
+    /** Sets the value of a global variable, defining it if it doesn't exist */
+    pub fn set_global(&mut self, name: &str, value: impl Into<LoxValue>) {
+        let name = self.strings.intern(name);
+        let value = self.intern(value.into());
+        let value = self.track(value);
+        self.globals.insert(name, value);
+    }
+
+    /** Returns the value of a global variable, if it is defined */
+    pub fn get_global(&self, name: &str) -> Option<LoxValue> {
+        // Names that were never interned can't belong to any global
+        let name = self.strings.find(name)?;
+        self.globals.get(&name).cloned()
+    }
+
+    /** Frees the objects that are only kept alive by reference cycles. This happens
+    automatically as programs create objects, so there's rarely a need to call it. */
+    pub fn collect_garbage(&mut self) {
+        let (stack, globals, frames) = (&self.stack, &self.globals, &self.frames);
+        let open_upvalues = &self.open_upvalues;
+
+        self.heap.collect(|marker| {
+            stack.iter().for_each(|val| marker.mark_value(&val.clone().into()));
+            globals.values().for_each(|val| marker.mark_value(val));
+            for frame in frames {
+                marker.mark(Object::Closure(frame.closure.clone()));
+            }
+            for upvalue in open_upvalues {
+                marker.mark(Object::Upvalue(upvalue.clone()));
+            }
+        });
+    }
+
+    /** Compiles and runs the source code. Globals defined by it
+    are kept in the VM, and can be used by later programs. */
     pub fn interpret(&mut self, source: &str) -> InterpretResult {
-        let mut parser = Parser::new(source);
-        let mut chunk = Chunk::new();
-        self.ip = 0;
+        let script = self.compile(source)?;
+        self.run_script(&script)?;
+        Ok(())
+    }
+
+    /** Compiles and runs the source code like `interpret`, returning
+    the value of the expression it ends with, or null if there isn't one */
+    pub fn eval(&mut self, source: &str) -> InterpretResult<LoxValue> {
+        let script = self.compile_eval(source)?;
+        self.run_script(&script)
+    }
+
+    /** Compiles the source code without running it */
+    pub fn compile(&mut self, source: &str) -> InterpretResult<Script> {
+        let function = Parser::new(source, &mut self.strings).compile().map_err(LoxError::Compile)?;
+        Ok(Script { function: Rc::new(function) })
+    }
+
+    /** Compiles the source code so that, when run, it returns
+    the value of the expression it ends with (see `eval`) */
+    pub fn compile_eval(&mut self, source: &str) -> InterpretResult<Script> {
+        let parser = Parser::new(source, &mut self.strings).return_last_expression();
+        let function = parser.compile().map_err(LoxError::Compile)?;
+        Ok(Script { function: Rc::new(function) })
+    }
+
+    /** Runs a compiled script, returning the value returned by it */
+    pub fn run_script(&mut self, script: &Script) -> InterpretResult<LoxValue> {
+        // The top-level script is run like any other function call
+        let closure = Rc::new(LoxClosure::new(script.function.clone(), vec![]));
+        self.call_from_rust(LoxValue::Object(LoxObject::Closure(closure)), &[])
+    }
+
+    /** Calls the Lox function stored in the global with the given name, returning its result */
+    pub fn call_function(&mut self, name: &str, args: &[LoxValue]) -> InterpretResult<LoxValue> {
+        match self.get_global(name) {
+            Some(callee) => self.call_from_rust(callee, args),
+            None => Err(RuntimeError::new(format!("Undefined variable '{name}'."), vec![]).into()),
+        }
+    }
+
+    fn call_from_rust(&mut self, callee: LoxValue, args: &[LoxValue]) -> InterpretResult<LoxValue> {
+        // These errors happen before the call runs any code, so their traces are empty.
+        // When a native makes the call, the frames that led to it are added later.
+        let arg_count = match u8::try_from(args.len()) {
+            Ok(count) => count,
+            Err(_) => {
+                let msg = "Can't have more than 255 arguments.".to_owned();
+                return Err(RuntimeError::new(msg, vec![]).into());
+            },
+        };
+
+        if self.nested_runs == MAX_NESTED_RUNS {
+            return Err(RuntimeError::new("Stack overflow.".to_owned(), vec![]).into());
+        }
 
-        if !parser.compile(&mut chunk) {
-            return InterpretResult::CompileError;
+        // Functions may be called from natives while some Lox code is already running,
+        // so errors only discard the frames and values that belong to this call
+        let (stack_base, frame_base) = (self.stack.len(), self.frames.len());
+        let outer_base = replace(&mut self.frame_base, frame_base);
+
+        self.nested_runs += 1;
+        let result = self.run_call(callee, args, arg_count);
+        self.nested_runs -= 1;
+        self.frame_base = outer_base;
+
+        if result.is_err() {
+            self.unwind(stack_base, frame_base);
+        }
+        result.map_err(LoxError::Runtime)
+    }
+
+    fn run_call(&mut self, callee: LoxValue, args: &[LoxValue], arg_count: u8) -> Result<LoxValue, RuntimeError> {
+        let depth = self.frames.len();
+        self.push(callee.clone());
+        for arg in args {
+            let arg = self.intern(arg.clone());
+            self.push(arg);
+        }
+
+        if let Err(err) = self.call_value(callee, arg_count) {
+            return Err(self.call_error(err));
         }
 
-        self.run(&chunk)
+        // Natives return right away, without pushing a new frame. Otherwise,
+        // we only run until the frame for this call is finished.
+        if self.frames.len() > depth {
+            self.run(depth)?;
+        }
+
+        Ok(self.pop())
     }
 
     //////////////////////////////////////////////////////////////////////////////////////////////////
 
-    fn run(&mut self, chunk: &Chunk) -> InterpretResult {
+    /** Runs the code in the current frame until the number of frames goes back to `depth`,
+    which leaves the value returned by the function of the last frame on the stack */
+    fn run(&mut self, depth: usize) -> Result<(), RuntimeError> {
         loop {
-            #[cfg(feature = "trace")]
-            self.trace(chunk);
+            if self.tracer.is_some() {
+                self.trace();
+            }
 
-            let op = self.read_byte(chunk);
+            let op = self.read_byte();
 
             match op {
                 OpCodes::OP_RETURN => {
-                    let val = self.pop();
-                    println!("{val}");
-                    return InterpretResult::Ok
+                    let result = self.pop_value();
+                    let frame = self.frames.pop().unwrap();
+
+                    // Discard the callee and its arguments and locals,
+                    // moving the captured ones to the heap
+                    self.close_upvalues(frame.slots);
+                    self.stack.truncate(frame.slots);
+                    self.stack.push(result);
+
+                    if self.frames.len() == depth {
+                        return Ok(());
+                    }
                 },
-                OpCodes::OP_CONSTANT => {
-                    let val = self.read_constant(chunk);
+                OpCodes::OP_CONSTANT | OpCodes::OP_CONSTANT_LONG => {
+                    let val = self.read_constant(op == OpCodes::OP_CONSTANT_LONG);
                     self.push(val);
                 },
                 OpCodes::OP_NEGATE => {
-                    if let Some(lox_val) = -self.pop() {
-                        self.push(lox_val);
-                    } else {
-                        runtime_error!(self, chunk, "Value must be a number.");
+                    let top = self.stack.len() - 1;
+                    match self.stack[top].as_number() {
+                        Some(n) => self.stack[top] = Value::from(-n),
+                        None => runtime_error!(self, "Value must be a number."),
+                    }
+                },
+                OpCodes::OP_ADD => {
+                    binary_op!(self, +);
+                    // Concatenation creates a new string, which is interned like the rest
+                    if self.stack[self.stack.len() - 1].as_number().is_none() {
+                        let val = self.pop();
+                        let val = self.intern(val);
+                        self.push(val);
                     }
                 },
-                OpCodes::OP_ADD => binary_op!(self, chunk, +),
-                OpCodes::OP_SUBSTRACT => binary_op!(self, chunk, -),
-                OpCodes::OP_MULTIPLY => binary_op!(self, chunk, *),
-                OpCodes::OP_DIVIDE => binary_op!(self, chunk, /),
+                OpCodes::OP_SUBSTRACT => binary_op!(self, -),
+                OpCodes::OP_MULTIPLY => binary_op!(self, *),
+                OpCodes::OP_DIVIDE => binary_op!(self, /),
+                OpCodes::OP_MODULO => binary_op!(self, %),
                 OpCodes::OP_NULL => self.push(LoxValue::Null),
                 OpCodes::OP_TRUE => self.push(LoxValue::Bool(true)),
                 OpCodes::OP_FALSE => self.push(LoxValue::Bool(false)),
                 OpCodes::OP_NOT => {
-                    let val = self.pop().is_falsey();
-                    self.push(LoxValue::Bool(val));
+                    let val = self.pop_value().is_falsey();
+                    self.stack.push(Value::from(val));
                 },
                 OpCodes::OP_EQUAL => {
-                    let val = self.pop() == self.pop();
-                    self.push(LoxValue::Bool(val));
-                },
-                OpCodes::OP_GREATER => {
-                    if let Some(lox_val) = self.pop().greater(&self.pop()) {
-                        self.push(lox_val);
-                    } else {
-                        runtime_error!(self, chunk, "Values must be numbers.");
+                    let val = self.pop_value() == self.pop_value();
+                    self.stack.push(Value::from(val));
+                },
+                OpCodes::OP_GREATER => comparison_op!(self, >),
+                OpCodes::OP_LESS => comparison_op!(self, <),
+                OpCodes::OP_POP => {
+                    self.pop_value();
+                },
+                OpCodes::OP_DEFINE_GLOBAL | OpCodes::OP_DEFINE_GLOBAL_LONG => {
+                    let name = self.read_string(op == OpCodes::OP_DEFINE_GLOBAL_LONG);
+                    let val = self.pop();
+                    self.globals.insert(name, val);
+                },
+                OpCodes::OP_GET_LOCAL => {
+                    let slot = self.frame().slots + self.read_byte() as usize;
+                    self.stack.push(self.stack[slot].clone());
+                },
+                OpCodes::OP_SET_LOCAL => {
+                    let slot = self.frame().slots + self.read_byte() as usize;
+                    self.stack[slot] = self.stack[self.stack.len() - 1].clone();
+                },
+                OpCodes::OP_JUMP => {
+                    let offset = self.read_short();
+                    self.frame_mut().ip += offset as usize;
+                },
+                OpCodes::OP_JUMP_IF_FALSE => {
+                    let offset = self.read_short();
+                    if self.stack[self.stack.len() - 1].is_falsey() {
+                        self.frame_mut().ip += offset as usize;
+                    }
+                },
+                OpCodes::OP_LOOP => {
+                    let offset = self.read_short();
+                    self.frame_mut().ip -= offset as usize;
+                },
+                OpCodes::OP_GET_GLOBAL | OpCodes::OP_GET_GLOBAL_LONG => {
+                    let name = self.read_string(op == OpCodes::OP_GET_GLOBAL_LONG);
+                    match self.globals.get(&name) {
+                        Some(val) => self.push(val.clone()),
+                        None => runtime_error!(self, "Undefined variable '{name}'."),
                     }
                 },
-                OpCodes::OP_LESS => {
-                    if let Some(lox_val) = self.pop().less(&self.pop()) {
-                        self.push(lox_val);
-                    } else {
-                        runtime_error!(self, chunk, "Values must be numbers.");
+                OpCodes::OP_SET_GLOBAL | OpCodes::OP_SET_GLOBAL_LONG => {
+                    let name = self.read_string(op == OpCodes::OP_SET_GLOBAL_LONG);
+                    let val = self.peek(0);
+                    // Assignment is an expression, so the value stays on the stack
+                    match self.globals.get_mut(&name) {
+                        Some(global) => *global = val,
+                        None => runtime_error!(self, "Undefined variable '{name}'."),
                     }
                 },
+                OpCodes::OP_CLASS | OpCodes::OP_CLASS_LONG => {
+                    let name = self.read_string(op == OpCodes::OP_CLASS_LONG);
+                    let class = self.track(LoxValue::Object(LoxObject::Class(Rc::new(LoxClass::new(name.to_string())))));
+                    self.push(class);
+                },
+                OpCodes::OP_METHOD | OpCodes::OP_METHOD_LONG => {
+                    let name = self.read_string(op == OpCodes::OP_METHOD_LONG);
+                    let method = match self.pop() {
+                        LoxValue::Object(LoxObject::Closure(closure)) => closure,
+                        _ => unreachable!(),
+                    };
+
+                    // The class stays on the stack until all methods are defined
+                    match self.peek(0) {
+                        LoxValue::Object(LoxObject::Class(class)) => class.methods.borrow_mut().insert(name, method),
+                        _ => unreachable!(),
+                    };
+                },
+                OpCodes::OP_GET_PROPERTY | OpCodes::OP_GET_PROPERTY_LONG => {
+                    let name = self.read_string(op == OpCodes::OP_GET_PROPERTY_LONG);
+                    let instance = match self.peek(0) {
+                        LoxValue::Object(LoxObject::Instance(instance)) => instance,
+                        _ => runtime_error!(self, "Only instances have properties."),
+                    };
+
+                    // Fields take priority over methods with the same name
+                    let field = instance.fields.borrow().get(&name).cloned();
+                    let val = match field {
+                        Some(val) => val,
+                        None => match self.bind_method(&instance.class, &name) {
+                            Some(bound) => bound,
+                            None => runtime_error!(self, "Undefined property '{name}'."),
+                        },
+                    };
+
+                    self.pop();
+                    self.push(val);
+                },
+                OpCodes::OP_SET_PROPERTY | OpCodes::OP_SET_PROPERTY_LONG => {
+                    let name = self.read_string(op == OpCodes::OP_SET_PROPERTY_LONG);
+                    let val = self.pop();
+                    match self.pop() {
+                        LoxValue::Object(LoxObject::Instance(instance)) => {
+                            instance.fields.borrow_mut().insert(name, val.clone());
+                        },
+                        _ => runtime_error!(self, "Only instances have fields."),
+                    }
+
+                    // Assignment is an expression, so the value stays on the stack
+                    self.push(val);
+                },
+                OpCodes::OP_INVOKE | OpCodes::OP_INVOKE_LONG => {
+                    let name = self.read_string(op == OpCodes::OP_INVOKE_LONG);
+                    let arg_count = self.read_byte();
+                    if let Err(err) = self.invoke(&name, arg_count) {
+                        return Err(self.call_error(err));
+                    }
+                },
+                OpCodes::OP_DUP => {
+                    self.stack.push(self.stack[self.stack.len() - 1].clone());
+                },
+                OpCodes::OP_DUP2 => {
+                    let len = self.stack.len();
+                    self.stack.push(self.stack[len - 2].clone());
+                    self.stack.push(self.stack[len - 1].clone());
+                },
+                OpCodes::OP_LIST => {
+                    let count = self.read_byte() as usize;
+                    let items = self.pop_many(count);
+                    let list = self.track(LoxValue::from(items));
+                    self.push(list);
+                },
+                OpCodes::OP_GET_INDEX => {
+                    let index = self.pop();
+                    let list = self.pop();
+                    let val = match self.list_index(&list, &index) {
+                        Ok((list, ix)) => list.items.borrow()[ix].clone(),
+                        Err(msg) => runtime_error!(self, "{msg}"),
+                    };
+                    self.push(val);
+                },
+                OpCodes::OP_SET_INDEX => {
+                    let val = self.pop();
+                    let index = self.pop();
+                    let list = self.pop();
+                    match self.list_index(&list, &index) {
+                        Ok((list, ix)) => list.items.borrow_mut()[ix] = val.clone(),
+                        Err(msg) => runtime_error!(self, "{msg}"),
+                    }
+
+                    // Assignment is an expression, so the value stays on the stack
+                    self.push(val);
+                },
+                OpCodes::OP_INHERIT => {
+                    let superclass = match self.peek(1) {
+                        LoxValue::Object(LoxObject::Class(class)) => class,
+                        _ => runtime_error!(self, "Superclass must be a class."),
+                    };
+
+                    // Methods are copied down into the subclass, which
+                    // can override them by defining its own afterwards
+                    if let LoxValue::Object(LoxObject::Class(subclass)) = self.pop() {
+                        let methods = superclass.methods.borrow().clone();
+                        subclass.methods.borrow_mut().extend(methods);
+                    }
+                },
+                OpCodes::OP_GET_SUPER | OpCodes::OP_GET_SUPER_LONG => {
+                    let name = self.read_string(op == OpCodes::OP_GET_SUPER_LONG);
+                    let superclass = self.pop_class();
+
+                    match self.bind_method(&superclass, &name) {
+                        Some(bound) => {
+                            self.pop();
+                            self.push(bound);
+                        },
+                        None => runtime_error!(self, "Undefined property '{name}'."),
+                    }
+                },
+                OpCodes::OP_SUPER_INVOKE | OpCodes::OP_SUPER_INVOKE_LONG => {
+                    let name = self.read_string(op == OpCodes::OP_SUPER_INVOKE_LONG);
+                    let arg_count = self.read_byte();
+                    let superclass = self.pop_class();
+
+                    if let Err(msg) = self.invoke_from_class(&superclass, &name, arg_count) {
+                        runtime_error!(self, "{msg}");
+                    }
+                },
+                OpCodes::OP_CALL => {
+                    let arg_count = self.read_byte();
+                    let callee = self.peek(arg_count as usize);
+                    if let Err(err) = self.call_value(callee, arg_count) {
+                        return Err(self.call_error(err));
+                    }
+                },
+                OpCodes::OP_CLOSURE | OpCodes::OP_CLOSURE_LONG => {
+                    let function = match self.read_constant(op == OpCodes::OP_CLOSURE_LONG) {
+                        LoxValue::Object(LoxObject::Function(func)) => func,
+                        _ => unreachable!(),
+                    };
+
+                    let upvalues = (0..function.upvalue_count).map(|_| {
+                        let is_local = self.read_byte() == 1;
+                        let index = self.read_byte() as usize;
+
+                        if is_local {
+                            self.capture_upvalue(self.frame().slots + index)
+                        } else {
+                            self.frame().closure.upvalues[index].clone()
+                        }
+                    }).collect();
+
+                    let closure = LoxClosure::new(function, upvalues);
+                    let closure = self.track(LoxValue::Object(LoxObject::Closure(Rc::new(closure))));
+                    self.push(closure);
+                },
+                OpCodes::OP_GET_UPVALUE => {
+                    let ix = self.read_byte() as usize;
+                    let val = match &*self.frame().closure.upvalues[ix].borrow() {
+                        LoxUpvalue::Open(slot) => self.stack[*slot].clone().into(),
+                        LoxUpvalue::Closed(val) => val.clone(),
+                    };
+                    self.push(val);
+                },
+                OpCodes::OP_SET_UPVALUE => {
+                    let ix = self.read_byte() as usize;
+                    let val = self.peek(0);
+                    let upvalue = self.frame().closure.upvalues[ix].clone();
+                    match &mut *upvalue.borrow_mut() {
+                        LoxUpvalue::Open(slot) => self.stack[*slot] = val.into(),
+                        LoxUpvalue::Closed(closed) => *closed = val,
+                    };
+                },
+                OpCodes::OP_CLOSE_UPVALUE => {
+                    self.close_upvalues(self.stack.len() - 1);
+                    self.pop();
+                },
                 _ => {},
             }
         }
     }
 
-    fn read_byte(&mut self, chunk: &Chunk) -> u8 {
-        let byte = chunk[self.ip];
-        self.ip += 1;
+    /** Returns the upvalue for the variable in the given stack slot,
+    reusing the existing one if it has already been captured */
+    fn capture_upvalue(&mut self, slot: usize) -> UpvalueRef {
+        let pos = self.open_upvalues.partition_point(|uv| match *uv.borrow() {
+            LoxUpvalue::Open(s) => s < slot,
+            LoxUpvalue::Closed(_) => unreachable!(),
+        });
+
+        if let Some(upvalue) = self.open_upvalues.get(pos) {
+            if matches!(*upvalue.borrow(), LoxUpvalue::Open(s) if s == slot) {
+                return upvalue.clone();
+            }
+        }
+
+        let upvalue = Rc::new(RefCell::new(LoxUpvalue::Open(slot)));
+        self.track_object(Object::Upvalue(upvalue.clone()));
+        self.open_upvalues.insert(pos, upvalue.clone());
+        upvalue
+    }
+
+    /** Closes all open upvalues pointing to the given stack slot or above it,
+    moving the variables they point to out of the stack */
+    fn close_upvalues(&mut self, last: usize) {
+        while let Some(upvalue) = self.open_upvalues.last() {
+            let slot = match *upvalue.borrow() {
+                LoxUpvalue::Open(slot) if slot >= last => slot,
+                _ => break,
+            };
+
+            let upvalue = self.open_upvalues.pop().unwrap();
+            *upvalue.borrow_mut() = LoxUpvalue::Closed(self.stack[slot].clone().into());
+        }
+    }
+
+    /** Calls a value with the given number of arguments, which are already on the stack */
+    fn call_value(&mut self, callee: LoxValue, arg_count: u8) -> Result<(), NativeError> {
+        let slot = self.stack.len() - arg_count as usize - 1;
+
+        match callee {
+            LoxValue::Object(LoxObject::Closure(closure)) => Ok(self.call(closure, arg_count)?),
+            LoxValue::Object(LoxObject::BoundMethod(bound)) => {
+                // The receiver takes the place of the callee, so it becomes `this`
+                self.stack[slot] = bound.receiver.clone().into();
+                Ok(self.call(bound.method.clone(), arg_count)?)
+            },
+            LoxValue::Object(LoxObject::Class(class)) => {
+                let instance = Rc::new(LoxInstance::new(class.clone()));
+                self.stack[slot] = self.track(LoxValue::Object(LoxObject::Instance(instance))).into();
+
+                let initializer = class.methods.borrow().get(&self.init_string).cloned();
+                match initializer {
+                    Some(init) => Ok(self.call(init, arg_count)?),
+                    None if arg_count != 0 => Err(format!("Expected 0 arguments but got {arg_count}.").into()),
+                    None => Ok(()),
+                }
+            },
+            LoxValue::Object(LoxObject::Native(native)) => {
+                if arg_count != native.arity {
+                    return Err(format!("Expected {} arguments but got {arg_count}.", native.arity).into());
+                }
+
+                // Natives don't get a call frame, they're run right away
+                // and their result replaces the callee and the arguments
+                let args = self.pop_many(arg_count as usize);
+                let result = (native.function)(self, &args)?;
+                let result = self.intern(result);
+                self.stack.truncate(slot);
+                self.push(result);
+                Ok(())
+            },
+            _ => Err("Can only call functions and classes.".into()),
+        }
+    }
+
+    /** Calls a method on the instance that is `arg_count` slots below the top of the stack */
+    fn invoke(&mut self, name: &Rc<LoxString>, arg_count: u8) -> Result<(), NativeError> {
+        let instance = match self.peek(arg_count as usize) {
+            LoxValue::Object(LoxObject::Instance(instance)) => instance,
+            _ => return Err("Only instances have methods.".into()),
+        };
+
+        // A field may contain a function, which is then called like any other value
+        let field = instance.fields.borrow().get(name).cloned();
+        if let Some(val) = field {
+            let slot = self.stack.len() - arg_count as usize - 1;
+            self.stack[slot] = val.clone().into();
+            return self.call_value(val, arg_count);
+        }
+
+        Ok(self.invoke_from_class(&instance.class, name, arg_count)?)
+    }
+
+    fn invoke_from_class(&mut self, class: &LoxClass, name: &Rc<LoxString>, arg_count: u8) -> Result<(), String> {
+        let method = class.methods.borrow().get(name).cloned();
+        match method {
+            Some(method) => self.call(method, arg_count),
+            None => Err(format!("Undefined property '{name}'.")),
+        }
+    }
+
+    /** Looks up a method in a class, binding it to the instance on top of the stack */
+    fn bind_method(&mut self, class: &LoxClass, name: &Rc<LoxString>) -> Option<LoxValue> {
+        let method = class.methods.borrow().get(name)?.clone();
+        let bound = LoxBoundMethod::new(self.peek(0), method);
+        Some(self.track(LoxValue::Object(LoxObject::BoundMethod(Rc::new(bound)))))
+    }
+
+    fn call(&mut self, closure: Rc<LoxClosure>, arg_count: u8) -> Result<(), String> {
+        let arity = closure.function.arity;
+        if arg_count != arity {
+            return Err(format!("Expected {arity} arguments but got {arg_count}."));
+        }
+
+        if self.frames.len() == self.max_frames {
+            return Err("Stack overflow.".to_owned());
+        }
+
+        let slots = self.stack.len() - arg_count as usize - 1;
+        self.frames.push(CallFrame { closure, ip: 0, slots });
+        Ok(())
+    }
+
+    /** Checks that a value is a list and that another one is a valid index for it */
+    fn list_index(&self, list: &LoxValue, index: &LoxValue) -> Result<(Rc<LoxList>, usize), String> {
+        let list = match list {
+            LoxValue::Object(LoxObject::List(list)) => list.clone(),
+            _ => return Err("Only lists can be indexed.".to_owned()),
+        };
+
+        let index = match index {
+            LoxValue::Number(n) if n.fract() == 0.0 => *n,
+            _ => return Err("List index must be an integer.".to_owned()),
+        };
+
+        let len = list.items.borrow().len();
+        if index < 0.0 || index >= len as f64 {
+            return Err(format!("List index {index} out of range for a list of length {len}."));
+        }
+
+        Ok((list, index as usize))
+    }
+
+    /** Replaces a string created outside of the string table with the interned one */
+    fn intern(&mut self, value: LoxValue) -> LoxValue {
+        match value {
+            LoxValue::Object(LoxObject::String(s)) => LoxValue::Object(LoxObject::String(self.strings.intern_rc(s))),
+            value => value,
+        }
+    }
+
+    /** Registers a new object with the garbage collector if it can be part of a cycle */
+    fn track(&mut self, value: LoxValue) -> LoxValue {
+        if let Some(object) = Object::from_value(&value) {
+            self.track_object(object);
+        }
+        value
+    }
+
+    fn track_object(&mut self, object: Object) {
+        // Collecting before the new object is tracked means it can't be freed by accident
+        if self.heap.should_collect() {
+            self.collect_garbage();
+        }
+        self.heap.track(&object);
+    }
+
+    fn frame(&self) -> &CallFrame {
+        self.frames.last().unwrap()
+    }
+
+    fn frame_mut(&mut self) -> &mut CallFrame {
+        self.frames.last_mut().unwrap()
+    }
+
+    fn chunk(&self) -> &Chunk {
+        &self.frame().closure.function.chunk
+    }
+
+    fn read_byte(&mut self) -> u8 {
+        let frame = self.frame_mut();
+        let byte = frame.closure.function.chunk[frame.ip];
+        frame.ip += 1;
         byte
     }
 
-    fn read_constant(&mut self, chunk: &Chunk) -> LoxValue {
-        let ix = self.read_byte(chunk);
-        chunk.values[ix as usize].clone()
+    fn read_short(&mut self) -> u16 {
+        let hi = self.read_byte();
+        let lo = self.read_byte();
+        u16::from_be_bytes([hi, lo])
+    }
+
+    /** Reads the index of a constant and returns the constant. The long forms
+    of the instructions use 3 bytes for the index, the rest only 1. */
+    fn read_constant(&mut self, long: bool) -> LoxValue {
+        let ix = if long {
+            u32::from_be_bytes([0, self.read_byte(), self.read_byte(), self.read_byte()]) as usize
+        } else {
+            self.read_byte() as usize
+        };
+        self.chunk().values[ix].clone()
+    }
+
+    fn read_string(&mut self, long: bool) -> Rc<LoxString> {
+        match self.read_constant(long) {
+            LoxValue::Object(LoxObject::String(s)) => s,
+            _ => unreachable!(),
+        }
+    }
+
+    /** Pops a value that the compiler guarantees to be a class */
+    fn pop_class(&mut self) -> Rc<LoxClass> {
+        match self.pop() {
+            LoxValue::Object(LoxObject::Class(class)) => class,
+            _ => unreachable!(),
+        }
+    }
+
+    fn peek(&self, distance: usize) -> LoxValue {
+        self.stack[self.stack.len() - 1 - distance].clone().into()
     }
 
     fn push(&mut self, val: LoxValue) {
-        self.stack[self.stack_top] = val;
-        self.stack_top += 1;
+        self.stack.push(val.into());
     }
 
     fn pop(&mut self) -> LoxValue {
-        // TODO: make it Copy again?
-        self.stack_top -= 1;
-        // When popping a value, we replace it with Null on the stack
-        // as it won't be used anymore. This ensures that values with
-        // allocated memory on the heap are moved out of the stack and
-        // their data is dropped when the value itself is after being used.
-        take(&mut self.stack[self.stack_top])
+        self.pop_value().into()
     }
 
-    fn reset_stack(&mut self) {
-        self.stack_top = 0;
+    /** Pops a value without converting it from the representation of the stack */
+    fn pop_value(&mut self) -> Value {
+        self.stack.pop().unwrap()
     }
 
-    //////////////////////////////////////////////////////////////////////////////////////////////////
-    /// Conditional debug features
+    /** Pops the given number of values, in the order they were pushed */
+    fn pop_many(&mut self, count: usize) -> Vec<LoxValue> {
+        let values = self.stack.split_off(self.stack.len() - count);
+        values.into_iter().map(LoxValue::from).collect()
+    }
 
-    #[cfg(feature = "trace")]
-    fn trace(&self, chunk: &Chunk) {
-        self.trace_stack();
-        self.trace_instr(chunk);
+    /** Builds an error with the current call stack */
+    fn runtime_error(&self, message: String) -> RuntimeError {
+        RuntimeError::new(message, self.trace_frames().collect())
     }
 
-    #[cfg(feature = "trace")]
-    fn trace_stack(&self) {
-        print!("[ ");
-        for v in &self.stack[..self.stack_top] {
-            print!("{v} ");
+    /** Builds the error for a failed call. Errors raised by Lox code that a native called
+    only have the frames of that code, so the ones that led to the native are added. */
+    fn call_error(&self, err: NativeError) -> RuntimeError {
+        match err {
+            NativeError::Message(message) => self.runtime_error(message),
+            NativeError::Runtime(mut err) => {
+                err.trace.extend(self.trace_frames());
+                err
+            },
         }
-        println!("] ");
     }
 
-    #[cfg(feature = "trace")]
-    fn trace_instr(&self, chunk: &Chunk) {
-        use std::io::stdout;
-        use crate::debug::debug_instruction;
+    /** Returns the frames of the current run, innermost first */
+    fn trace_frames(&self) -> impl Iterator<Item = TraceFrame> + '_ {
+        self.frames[self.frame_base ..].iter().rev().map(|frame| {
+            let function = &frame.closure.function;
+            let position = function.chunk.position(frame.ip - 1);
+            TraceFrame {
+                function: function.name.clone(),
+                line: position.line,
+                column: position.column,
+            }
+        })
+    }
 
-        let mut f = stdout().lock();
-        debug_instruction(&mut f, chunk, self.ip);
+    /** Discards the frames and values above the given ones, after an error */
+    fn unwind(&mut self, stack_base: usize, frame_base: usize) {
+        // Closures created before the error may outlive it, so
+        // the variables they captured must be moved off the stack
+        self.close_upvalues(stack_base);
+        self.stack.truncate(stack_base);
+        self.frames.truncate(frame_base);
+    }
+
+    //////////////////////////////////////////////////////////////////////////////////////////////////
+    // Debug features
+
+    fn trace(&mut self) {
+        let Some(mut tracer) = self.tracer.take() else { return };
+        let frame = self.frame();
+
+        // If the trace can't be written, tracing stops but the program goes on
+        if tracer.trace(&frame.closure.function, frame.ip, &self.stack).is_ok() {
+            self.tracer = Some(tracer);
+        }
     }
 }
 
-impl InterpretResult {
-    pub fn exit_code(&self) -> i32 {
-        match self {
-            InterpretResult::Ok => 0,
-            InterpretResult::CompileError => 65,
-            InterpretResult::RuntimeError => 70,
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Calls the global function with the given name, passing on its errors
+    fn call(vm: &mut VM, args: &[LoxValue]) -> Result<LoxValue, NativeError> {
+        Ok(vm.call_function(&args[0].to_string(), &[])?)
+    }
+
+    fn functions(err: &LoxError) -> Vec<Option<String>> {
+        match err {
+            LoxError::Runtime(err) => err.trace.iter()
+                .map(|frame| frame.function.as_ref().map(|name| name.to_string()))
+                .collect(),
+            _ => panic!("Expected a runtime error, got {err}"),
         }
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn errors_passed_on_by_natives_keep_their_trace() {
+        let mut vm = VM::new();
+        vm.define_native("call", 1, call);
+        let err = vm.interpret("
+            fn bad() { return 1 + null; }
+            fn outer() { return call(\"bad\"); }
+            outer();
+        ").unwrap_err();
+
+        assert_eq!(err.to_string().lines().next(), Some("Values have incompatible types."));
+        assert_eq!(functions(&err), [Some("bad".to_owned()), Some("outer".to_owned()), None]);
+    }
+
+    #[test]
+    fn errors_before_nested_calls_run_are_traced_once() {
+        let mut vm = VM::new();
+        vm.define_native("call", 1, call);
+        let err = vm.interpret("
+            fn outer() { return call(\"missing\"); }
+            outer();
+        ").unwrap_err();
+
+        assert_eq!(functions(&err), [Some("outer".to_owned()), None]);
+    }
+}
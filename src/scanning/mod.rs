@@ -1,5 +1,5 @@
 mod scanner;
 mod token;
 
-pub use scanner::Scanner;
+pub use scanner::{is_incomplete, Scanner};
 pub use token::{Token, TokenType};
\ No newline at end of file
@@ -1,44 +1,59 @@
 use super::{Token, TokenType::{self, *}};
 
+const UNTERMINATED_STRING: &str = "Unterminated string at the end of file.";
+
+#[derive(Clone)]
 pub struct Scanner<'a> {
     source: &'a str,
     start: usize,
     current: usize,
     line: u32,
+    // Column of the next character to be consumed
+    column: u32,
+    // Position where the current token starts, tokens spanning
+    // multiple lines are reported at their first line
+    start_line: u32,
+    start_column: u32,
 }
 
 impl<'src> Scanner<'src> {
     pub fn new(source: &'src str) -> Self {
-        Self { source, start: 0, current: 0, line: 1 }
+        Self { source, start: 0, current: 0, line: 1, column: 1, start_line: 1, start_column: 1 }
     }
 
     pub fn scan_next_token(&mut self) -> Token<'src> {
         self.skip_whitespace();
         self.start = self.current;
+        self.start_line = self.line;
+        self.start_column = self.column;
 
         if self.is_at_end() {
             return self.make_token(Eof);
         }
 
         match self.consume() {
-            "{" => self.make_token(LeftBrace),
-            "}" => self.make_token(RightBrace),
-            "(" => self.make_token(LeftParen),
-            ")" => self.make_token(RightParen),
-            "," => self.make_token(Comma),
-            "." => self.make_token(Dot),
-            "-" => self.make_token(Minus),
-            "+" => self.make_token(Plus),
-            ";" => self.make_token(Semicolon),
-            "*" => self.make_token(Asterisk),
-            "/" => self.make_token(Slash), // Comments are handled by skip_whitespace()
-            "!" => self.make_token_if("=", NotEqual, Not),
-            "=" => self.make_token_if("=", EqualEqual, Equal),
-            ">" => self.make_token_if("=", GreaterEqual, Greater),
-            "<" => self.make_token_if("=", LessEqual, Less),
-            "\"" | "'" => self.make_string(),
-            s if is_digit(s) => self.make_number(),
-            s if is_alpha(s) => self.make_identifier(),
+            '{' => self.make_token(LeftBrace),
+            '}' => self.make_token(RightBrace),
+            '(' => self.make_token(LeftParen),
+            ')' => self.make_token(RightParen),
+            '[' => self.make_token(LeftBracket),
+            ']' => self.make_token(RightBracket),
+            ',' => self.make_token(Comma),
+            '.' => self.make_token(Dot),
+            '-' => self.make_token_if('=', MinusEqual, Minus),
+            '+' => self.make_token_if('=', PlusEqual, Plus),
+            ';' => self.make_token(Semicolon),
+            ':' => self.make_token(Colon),
+            '*' => self.make_token_if('=', AsteriskEqual, Asterisk),
+            '/' => self.make_token_if('=', SlashEqual, Slash), // Comments are handled by skip_whitespace()
+            '%' => self.make_token_if('=', PercentEqual, Percent),
+            '!' => self.make_token_if('=', NotEqual, Not),
+            '=' => self.make_token_if('=', EqualEqual, Equal),
+            '>' => self.make_token_if('=', GreaterEqual, Greater),
+            '<' => self.make_token_if('=', LessEqual, Less),
+            quote @ ('"' | '\'') => self.make_string(quote),
+            c if c.is_ascii_digit() => self.make_number(),
+            c if is_alpha(c) => self.make_identifier(),
              _ => self.make_error_token("Unexpected character."),
         }
    }
@@ -49,16 +64,16 @@ impl<'src> Scanner<'src> {
     fn skip_whitespace(&mut self) {
         while !self.is_at_end() {
             match self.peek() {
-                " " | "\t" | "\r" => {
+                ' ' | '\t' | '\r' => {
                     self.consume();
                 },
-                "\n" => {
+                '\n' => {
                     self.consume();
-                    self.line += 1;
+                    self.new_line();
                 },
-                "/" if self.peek_forward() == "/" => {
+                '/' if self.peek_forward() == '/' => {
                     // Line comment, skip forward til the end of the line
-                    while self.peek() != "\n" && !self.is_at_end() {
+                    while self.peek() != '\n' && !self.is_at_end() {
                         self.consume();
                     }
                 }
@@ -69,28 +84,23 @@ impl<'src> Scanner<'src> {
 
     fn make_token(&self, kind: TokenType) -> Token<'src> {
         let literal = &self.source[self.start .. self.current];
-        Token { literal, kind, line: self.line }
+        Token { literal, kind, line: self.start_line, column: self.start_column, offset: self.start }
     }
 
-    fn make_token_if(&mut self, expected: &str, yes: TokenType, no: TokenType) -> Token<'src> {
+    fn make_token_if(&mut self, expected: char, yes: TokenType, no: TokenType) -> Token<'src> {
         let kind = if self.matches(expected) { yes } else { no };
         self.make_token(kind)
     }
 
-    fn make_string(&mut self) -> Token<'src> {
-        let closing = char(self.peek_back());
-        let mut peek;
-
-        while !self.is_at_end() && {peek = self.peek(); char(peek)} != closing {
-            if peek == "\n" {
-                self.line += 1;
+    fn make_string(&mut self, closing: char) -> Token<'src> {
+        while !self.is_at_end() && self.peek() != closing {
+            if self.consume() == '\n' {
+                self.new_line();
             }
-
-            self.consume();
         }
 
         if self.is_at_end() {
-            self.make_error_token("Unterminated string at the end of file.")
+            self.make_error_token(UNTERMINATED_STRING)
         } else {
             // Consume the closing quote
             self.consume();
@@ -99,15 +109,15 @@ impl<'src> Scanner<'src> {
     }
 
     fn make_number(&mut self) -> Token<'src> {
-        while is_digit(self.peek()) {
+        while self.peek().is_ascii_digit() {
             self.consume();
         }
 
-        if self.peek() == "." && is_digit(self.peek_forward()) {
+        if self.peek() == '.' && self.peek_forward().is_ascii_digit() {
             // Consume the decimals dot
             self.consume();
 
-            while is_digit(self.peek()) {
+            while self.peek().is_ascii_digit() {
                 self.consume();
             }
         }
@@ -116,8 +126,7 @@ impl<'src> Scanner<'src> {
     }
 
     fn make_identifier(&mut self) -> Token<'src> {
-        let mut peek;
-        while {peek = self.peek(); is_digit(peek) || is_alpha(peek)} {
+        while self.peek().is_ascii_digit() || is_alpha(self.peek()) {
             self.consume();
         }
 
@@ -126,37 +135,36 @@ impl<'src> Scanner<'src> {
     }
 
     fn make_error_token(&self, literal: &'src str) -> Token<'src> {
-        Token { literal, kind: Error, line: self.line }
+        Token { literal, kind: Error, line: self.start_line, column: self.start_column, offset: self.start }
     }
 
-     fn consume(&mut self) -> &str {
-        self.current += 1;
-        &self.source[self.current - 1 .. self.current]
+    /** Registers that a newline character has just been consumed */
+    fn new_line(&mut self) {
+        self.line += 1;
+        self.column = 1;
     }
 
-    fn peek(&self) -> &str {
-        if self.is_at_end() { // TODO: fix this hack
-            "\0"
-        } else {
-            &self.source[self.current .. self.current + 1]
-        }
+    fn consume(&mut self) -> char {
+        let c = self.peek();
+        self.current += c.len_utf8();
+        self.column += 1;
+        c
     }
 
-    fn peek_forward(&self) -> &str {
-        if self.is_at_end() {
-            "\0"
-        } else {
-            &self.source[self.current + 1 .. self.current + 2]
-        }
+    /** Returns the next character without consuming it, or '\0' at the end of the source code */
+    fn peek(&self) -> char {
+        self.source[self.current ..].chars().next().unwrap_or('\0')
     }
 
-    fn peek_back(&self) -> &str {
-        &self.source[self.current - 1 .. self.current]
+    fn peek_forward(&self) -> char {
+        let mut chars = self.source[self.current ..].chars();
+        chars.next();
+        chars.next().unwrap_or('\0')
     }
 
-    fn matches(&mut self, expected: &str) -> bool {
+    fn matches(&mut self, expected: char) -> bool {
         let consumed = !self.is_at_end() && self.peek() == expected;
-        if consumed { self.current += 1 }
+        if consumed { self.consume(); }
         consumed
     }
 
@@ -165,14 +173,59 @@ impl<'src> Scanner<'src> {
     }
 }
 
-fn char(s: &str) -> char {
-    s.chars().next().unwrap()
+fn is_alpha(c: char) -> bool {
+    matches!(c, 'a'..='z' | 'A'..='Z' | '_')
 }
 
-fn is_digit(s: &str) -> bool {
-    char(s).is_ascii_digit()
+/** Checks whether the source code ends inside a string or with unclosed
+parentheses, brackets or braces, which means that more code is expected after it */
+pub fn is_incomplete(source: &str) -> bool {
+    let mut scanner = Scanner::new(source);
+    let mut depth: i64 = 0;
+
+    loop {
+        let token = scanner.scan_next_token();
+        match token.kind {
+            LeftParen | LeftBracket | LeftBrace => depth += 1,
+            RightParen | RightBracket | RightBrace => depth -= 1,
+            Error if token.literal == UNTERMINATED_STRING => return true,
+            Eof => return depth > 0,
+            _ => {},
+        }
+    }
 }
 
-fn is_alpha(s: &str) -> bool {
-    matches!(char(s), 'a'..='z' | 'A'..='Z' | '_')
-}
\ No newline at end of file
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /** Returns the kind, line and column of every token in the source code */
+    fn scan(source: &str) -> Vec<(TokenType, u32, u32)> {
+        let mut scanner = Scanner::new(source);
+        let mut tokens = vec![];
+        loop {
+            let token = scanner.scan_next_token();
+            tokens.push((token.kind, token.line, token.column));
+            if token.kind == Eof {
+                return tokens;
+            }
+        }
+    }
+
+    #[test]
+    fn columns_count_characters() {
+        let tokens = scan("let s = \"héllo 日本\"; // café ☕\nprint(s); é");
+        assert_eq!(tokens, [
+            (Let, 1, 1), (Identifier, 1, 5), (Equal, 1, 7), (String, 1, 9), (Semicolon, 1, 19),
+            (Identifier, 2, 1), (LeftParen, 2, 6), (Identifier, 2, 7), (RightParen, 2, 8), (Semicolon, 2, 9),
+            (Error, 2, 11), (Eof, 2, 12),
+        ]);
+    }
+
+    #[test]
+    fn strings_keep_their_characters() {
+        let mut scanner = Scanner::new("'ça' \"€\"");
+        assert_eq!(scanner.scan_next_token().literal, "'ça'");
+        assert_eq!(scanner.scan_next_token().literal, "\"€\"");
+    }
+}
@@ -6,8 +6,11 @@ pub enum TokenType {
     // the order of the parsing table in parsing::utils
 
     // Single-characters
-    LeftParen, RightParen, LeftBrace, RightBrace,
-    Comma, Dot, Minus, Plus, Semicolon, Slash, Asterisk,
+    LeftParen, RightParen, LeftBrace, RightBrace, LeftBracket, RightBracket,
+    Comma, Dot, Minus, Plus, Semicolon, Colon, Slash, Asterisk, Percent,
+
+    // Compound assignment
+    PlusEqual, MinusEqual, AsteriskEqual, SlashEqual, PercentEqual,
 
     // Boolean operators
     Not, NotEqual, Equal, EqualEqual,
@@ -18,8 +21,8 @@ pub enum TokenType {
 
     // Keywords
     And, Class, If, Else, True, False,
-    For, Fn, Null, Or, Print, Return,
-    Super, This, Let, While,
+    For, Fn, Null, Or, Return,
+    Super, This, Let, While, Break, Continue,
 
     // Aux
     Eof,
@@ -31,6 +34,10 @@ pub enum TokenType {
 pub struct Token<'src> {
     pub kind: TokenType,
     pub line: u32,
+    // 1-based, in characters
+    pub column: u32,
+    // Byte offset of the token within the source
+    pub offset: usize,
     pub literal: &'src str,
 }
 
@@ -38,7 +45,9 @@ impl TokenType {
     pub fn from_literal(literal: &str) -> Self {
         match literal {
             "and" => And,
+            "break" => Break,
             "class" => Class,
+            "continue" => Continue,
             "else" => Else,
             "false" => False,
             "for" => For,
@@ -47,7 +56,6 @@ impl TokenType {
             "let" => Let,
             "null" => Null,
             "or" => Or,
-            "print" => Print,
             "return" => Return,
             "super" => Super,
             "this" => This,
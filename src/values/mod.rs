@@ -1,5 +1,18 @@
+#[cfg(feature = "nan-boxing")]
+mod nanbox;
 mod object;
+mod string;
 mod value;
 
-pub use object::LoxObject;
-pub use value::LoxValue;
\ No newline at end of file
+pub use object::{
+    LoxObject, LoxFunction, LoxClosure, LoxUpvalue, UpvalueRef,
+    LoxClass, LoxInstance, LoxBoundMethod, LoxNative, NativeFn, LoxList,
+};
+pub use string::{LoxString, StringMap, StringTable};
+pub use value::LoxValue;
+
+/// Representation of the values in the VM's stack
+#[cfg(feature = "nan-boxing")]
+pub use nanbox::Value;
+#[cfg(not(feature = "nan-boxing"))]
+pub type Value = LoxValue;
@@ -0,0 +1,305 @@
+//! NaN-boxed representation of the values in the VM's stack.
+//!
+//! A double that is a quiet NaN only uses a few of its 64 bits, so the rest can hold
+//! something else. Numbers are stored as their own bits, and every other value is
+//! stored as a NaN that no arithmetic operation produces: null and booleans are small
+//! constants, and objects keep the pointer to their contents. This makes every value
+//! fit in 8 bytes, instead of the 16 bytes of a `LoxValue`.
+//!
+//! The instructions that run most often, like arithmetic, comparisons and jumps,
+//! work on these values directly. The rest of them convert values to and from
+//! `LoxValue` as they are pushed and popped, and so does the public API.
+
+use std::fmt;
+use std::mem::ManuallyDrop;
+use std::rc::Rc;
+use crate::values::{
+    LoxValue, LoxObject, LoxString, LoxFunction, LoxClosure,
+    LoxClass, LoxInstance, LoxBoundMethod, LoxNative, LoxList,
+};
+
+#[cfg(not(target_pointer_width = "64"))]
+compile_error!("NaN-boxing needs 64-bit pointers, build without the `nan-boxing` feature.");
+
+// A double is a quiet NaN when all of these bits are set. The NaNs produced
+// by arithmetic don't set the lowest one, so they never look like a boxed value.
+const QNAN: u64 = 0x7ffc_0000_0000_0000;
+const SIGN_BIT: u64 = 0x8000_0000_0000_0000;
+
+const NULL: u64 = QNAN | 1;
+const FALSE: u64 = QNAN | 2;
+const TRUE: u64 = QNAN | 3;
+
+// Objects have the sign bit set, and their pointer in the lowest 48 bits. Objects
+// are aligned to 8 bytes, so the lowest 3 bits of the pointer are free to store
+// the kind of object it points to.
+const OBJECT: u64 = SIGN_BIT | QNAN;
+const POINTER_MASK: u64 = 0x0000_ffff_ffff_fff8;
+const KIND_MASK: u64 = 0x7;
+
+const KIND_STRING: u64 = 0;
+const KIND_FUNCTION: u64 = 1;
+const KIND_CLOSURE: u64 = 2;
+const KIND_CLASS: u64 = 3;
+const KIND_INSTANCE: u64 = 4;
+const KIND_BOUND_METHOD: u64 = 5;
+const KIND_NATIVE: u64 = 6;
+const KIND_LIST: u64 = 7;
+
+/// A value packed into 64 bits. Objects hold one of the handles to
+/// their contents, which is released when the value is dropped.
+pub struct Value(u64);
+
+impl Value {
+    pub fn is_falsey(&self) -> bool {
+        self.0 == NULL || self.0 == FALSE
+    }
+
+    pub fn as_number(&self) -> Option<f64> {
+        if self.0 & QNAN != QNAN { Some(f64::from_bits(self.0)) } else { None }
+    }
+
+    fn is_object(&self) -> bool {
+        self.0 & OBJECT == OBJECT
+    }
+
+    /** Unpacks the value without taking over its handle, if it is an object */
+    fn view(&self) -> ManuallyDrop<LoxValue> {
+        // The view can't be dropped, so the handle stays with `self`
+        ManuallyDrop::new(unsafe { self.unpack() })
+    }
+
+    /** Unpacks the value. Safety: for objects, the returned value takes over the
+    handle held by this one, so only one of them can be dropped. */
+    unsafe fn unpack(&self) -> LoxValue {
+        match self.0 {
+            NULL => LoxValue::Null,
+            FALSE => LoxValue::Bool(false),
+            TRUE => LoxValue::Bool(true),
+            _ if !self.is_object() => LoxValue::Number(f64::from_bits(self.0)),
+            bits => {
+                let ptr = (bits & POINTER_MASK) as usize;
+                let object = match bits & KIND_MASK {
+                    KIND_STRING => LoxObject::String(Rc::from_raw(ptr as *const LoxString)),
+                    KIND_FUNCTION => LoxObject::Function(Rc::from_raw(ptr as *const LoxFunction)),
+                    KIND_CLOSURE => LoxObject::Closure(Rc::from_raw(ptr as *const LoxClosure)),
+                    KIND_CLASS => LoxObject::Class(Rc::from_raw(ptr as *const LoxClass)),
+                    KIND_INSTANCE => LoxObject::Instance(Rc::from_raw(ptr as *const LoxInstance)),
+                    KIND_BOUND_METHOD => LoxObject::BoundMethod(Rc::from_raw(ptr as *const LoxBoundMethod)),
+                    KIND_NATIVE => LoxObject::Native(Rc::from_raw(ptr as *const LoxNative)),
+                    _ => LoxObject::List(Rc::from_raw(ptr as *const LoxList)),
+                };
+                LoxValue::Object(object)
+            },
+        }
+    }
+}
+
+impl From<LoxValue> for Value {
+    fn from(value: LoxValue) -> Self {
+        match value {
+            LoxValue::Null => Self(NULL),
+            LoxValue::Bool(b) => Self::from(b),
+            LoxValue::Number(n) => Self::from(n),
+            LoxValue::Object(object) => {
+                // The handle moves into the boxed value, and is released when it is dropped
+                let (ptr, kind) = match object {
+                    LoxObject::String(rc) => (Rc::into_raw(rc) as u64, KIND_STRING),
+                    LoxObject::Function(rc) => (Rc::into_raw(rc) as u64, KIND_FUNCTION),
+                    LoxObject::Closure(rc) => (Rc::into_raw(rc) as u64, KIND_CLOSURE),
+                    LoxObject::Class(rc) => (Rc::into_raw(rc) as u64, KIND_CLASS),
+                    LoxObject::Instance(rc) => (Rc::into_raw(rc) as u64, KIND_INSTANCE),
+                    LoxObject::BoundMethod(rc) => (Rc::into_raw(rc) as u64, KIND_BOUND_METHOD),
+                    LoxObject::Native(rc) => (Rc::into_raw(rc) as u64, KIND_NATIVE),
+                    LoxObject::List(rc) => (Rc::into_raw(rc) as u64, KIND_LIST),
+                };
+
+                debug_assert_eq!(ptr & !POINTER_MASK, 0, "Object pointer doesn't fit in a NaN-boxed value.");
+                Self(OBJECT | ptr | kind)
+            },
+        }
+    }
+}
+
+impl From<f64> for Value {
+    fn from(n: f64) -> Self {
+        // NaNs are all stored the same way, so that none of them looks like a boxed value
+        if n.is_nan() { Self(f64::NAN.to_bits()) } else { Self(n.to_bits()) }
+    }
+}
+
+impl From<bool> for Value {
+    fn from(b: bool) -> Self {
+        if b { Self(TRUE) } else { Self(FALSE) }
+    }
+}
+
+impl From<Value> for LoxValue {
+    fn from(value: Value) -> Self {
+        // The handle moves into the unpacked value, so the boxed one must not release it
+        let value = ManuallyDrop::new(value);
+        unsafe { value.unpack() }
+    }
+}
+
+impl Clone for Value {
+    fn clone(&self) -> Self {
+        if self.is_object() {
+            Self::from(LoxValue::clone(&self.view()))
+        } else {
+            Self(self.0)
+        }
+    }
+}
+
+impl Drop for Value {
+    fn drop(&mut self) {
+        if self.is_object() {
+            drop(unsafe { self.unpack() });
+        }
+    }
+}
+
+impl PartialEq for Value {
+    fn eq(&self, other: &Self) -> bool {
+        match (self.as_number(), other.as_number()) {
+            // Compared as doubles, since NaN isn't equal to itself and 0 is equal to -0
+            (Some(a), Some(b)) => a == b,
+            _ if self.is_object() && other.is_object() => *self.view() == *other.view(),
+            _ => self.0 == other.0,
+        }
+    }
+}
+
+impl fmt::Debug for Value {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Debug::fmt(&*self.view(), f)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn round_trip(value: LoxValue) -> LoxValue {
+        LoxValue::from(Value::from(value))
+    }
+
+    fn objects() -> Vec<LoxObject> {
+        let function = Rc::new(LoxFunction::new(Some("f")));
+        let closure = Rc::new(LoxClosure::new(function.clone(), vec![]));
+        let class = Rc::new(LoxClass::new("A".to_owned()));
+        let instance = LoxValue::Object(LoxObject::Instance(Rc::new(LoxInstance::new(class.clone()))));
+
+        vec![
+            LoxObject::String(Rc::new(LoxString::new("text"))),
+            LoxObject::Function(function),
+            LoxObject::Closure(closure.clone()),
+            LoxObject::Class(class),
+            LoxObject::BoundMethod(Rc::new(LoxBoundMethod::new(instance.clone(), closure))),
+            LoxObject::Native(Rc::new(LoxNative::new("n", 0, |_, _| Ok(LoxValue::Null)))),
+            LoxObject::List(Rc::new(LoxList::new(vec![LoxValue::Number(1.0)]))),
+            match instance { LoxValue::Object(object) => object, _ => unreachable!() },
+        ]
+    }
+
+    fn strong_count(object: &LoxObject) -> usize {
+        match object {
+            LoxObject::String(rc) => Rc::strong_count(rc),
+            LoxObject::Function(rc) => Rc::strong_count(rc),
+            LoxObject::Closure(rc) => Rc::strong_count(rc),
+            LoxObject::Class(rc) => Rc::strong_count(rc),
+            LoxObject::Instance(rc) => Rc::strong_count(rc),
+            LoxObject::BoundMethod(rc) => Rc::strong_count(rc),
+            LoxObject::Native(rc) => Rc::strong_count(rc),
+            LoxObject::List(rc) => Rc::strong_count(rc),
+        }
+    }
+
+    #[test]
+    fn primitives_round_trip() {
+        let values = [
+            LoxValue::Null,
+            LoxValue::Bool(true),
+            LoxValue::Bool(false),
+            LoxValue::Number(0.0),
+            LoxValue::Number(-0.0),
+            LoxValue::Number(1.5),
+            LoxValue::Number(f64::INFINITY),
+            LoxValue::Number(f64::NEG_INFINITY),
+            LoxValue::Number(f64::MIN_POSITIVE),
+            LoxValue::Number(f64::MAX),
+        ];
+
+        for value in values {
+            assert_eq!(round_trip(value.clone()), value);
+        }
+
+        // -0 is equal to 0, so its sign is checked separately
+        assert!(round_trip(LoxValue::Number(-0.0)).as_number().unwrap().is_sign_negative());
+    }
+
+    #[test]
+    fn nans_are_canonical() {
+        // NaNs with the sign bit or a payload could look like boxed values otherwise
+        let nans = [f64::NAN, -f64::NAN, f64::from_bits(QNAN | 0xdead), f64::from_bits(OBJECT | 0x1000)];
+
+        for nan in nans {
+            let value = Value::from(nan);
+            assert!(!value.is_object());
+            assert!(value.as_number().unwrap().is_nan());
+            assert!(matches!(LoxValue::from(value), LoxValue::Number(n) if n.is_nan()));
+        }
+    }
+
+    #[test]
+    fn objects_round_trip() {
+        for object in objects() {
+            let before = strong_count(&object);
+            let value = Value::from(LoxValue::Object(object.clone()));
+            assert!(value.is_object());
+            assert_eq!(strong_count(&object), before + 1);
+
+            // The handle moves back out of the boxed value
+            let unpacked = LoxValue::from(value);
+            assert_eq!(strong_count(&object), before + 1);
+            assert_eq!(unpacked, LoxValue::Object(object.clone()));
+
+            drop(unpacked);
+            assert_eq!(strong_count(&object), before);
+        }
+    }
+
+    #[test]
+    fn clones_and_drops_keep_counts() {
+        for object in objects() {
+            let before = strong_count(&object);
+            let value = Value::from(LoxValue::Object(object.clone()));
+
+            let copy = value.clone();
+            assert_eq!(strong_count(&object), before + 2);
+            assert!(copy == value);
+            assert_eq!(format!("{copy:?}"), format!("{:?}", LoxValue::Object(object.clone())));
+
+            drop(value);
+            assert_eq!(strong_count(&object), before + 1);
+            drop(copy);
+            assert_eq!(strong_count(&object), before);
+        }
+    }
+
+    #[test]
+    fn equality_matches_lox_values() {
+        let string = LoxValue::from("a");
+        let values = [
+            LoxValue::Null, LoxValue::Bool(false), LoxValue::Number(0.0), LoxValue::Number(-0.0),
+            LoxValue::Number(f64::NAN), string.clone(), string, LoxValue::from("a"), LoxValue::from("b"),
+        ];
+
+        for a in &values {
+            for b in &values {
+                assert_eq!(Value::from(a.clone()) == Value::from(b.clone()), a == b, "{a:?} == {b:?}");
+            }
+        }
+    }
+}
@@ -1,4 +1,138 @@
-#[derive(Clone, PartialEq, Eq)]
+use std::cell::RefCell;
+use std::rc::Rc;
+use crate::errors::NativeError;
+use crate::runtime::{Chunk, VM};
+use crate::values::{LoxString, LoxValue, StringMap};
+
+#[derive(Clone)]
 pub enum LoxObject {
-    String(String),
+    String(Rc<LoxString>),
+    Function(Rc<LoxFunction>),
+    Closure(Rc<LoxClosure>),
+    Class(Rc<LoxClass>),
+    Instance(Rc<LoxInstance>),
+    BoundMethod(Rc<LoxBoundMethod>),
+    Native(Rc<LoxNative>),
+    List(Rc<LoxList>),
 }
+
+#[derive(Default)]
+pub struct LoxFunction {
+    // None for the top-level script
+    pub name: Option<String>,
+    pub arity: u8,
+    pub(crate) upvalue_count: usize,
+    pub(crate) chunk: Chunk,
+}
+
+/// A function together with the variables it has captured
+pub struct LoxClosure {
+    pub function: Rc<LoxFunction>,
+    pub(crate) upvalues: Vec<UpvalueRef>,
+}
+
+/// A variable captured by a closure. It points to the variable's stack slot
+/// while it's still in scope, and holds the value itself once it goes out
+/// of scope, so that it outlives the function that declared it.
+pub enum LoxUpvalue {
+    Open(usize),
+    Closed(LoxValue),
+}
+
+/// Upvalues are shared by all closures that capture the same variable
+pub type UpvalueRef = Rc<RefCell<LoxUpvalue>>;
+
+pub struct LoxClass {
+    pub name: String,
+    // Methods are added one by one after the class is created
+    pub(crate) methods: RefCell<StringMap<Rc<LoxClosure>>>,
+}
+
+pub struct LoxInstance {
+    pub class: Rc<LoxClass>,
+    pub fields: RefCell<StringMap<LoxValue>>,
+}
+
+/// A method accessed through an instance, which remembers the
+/// instance so that it can be used as `this` when called later
+pub struct LoxBoundMethod {
+    pub receiver: LoxValue,
+    pub method: Rc<LoxClosure>,
+}
+
+impl LoxFunction {
+    pub(crate) fn new(name: Option<&str>) -> Self {
+        Self { name: name.map(str::to_owned), arity: 0, upvalue_count: 0, chunk: Chunk::new() }
+    }
+}
+
+impl LoxClosure {
+    pub(crate) fn new(function: Rc<LoxFunction>, upvalues: Vec<UpvalueRef>) -> Self {
+        Self { function, upvalues }
+    }
+}
+
+pub struct LoxList {
+    pub items: RefCell<Vec<LoxValue>>,
+}
+
+/// Signature of the Rust functions that can be called from Lox code.
+/// They receive the VM and the arguments of the call, and return either
+/// the resulting value or an error, which may come from Lox code they called.
+pub type NativeFn = fn(&mut VM, &[LoxValue]) -> Result<LoxValue, NativeError>;
+
+pub struct LoxNative {
+    pub name: String,
+    pub arity: u8,
+    pub function: NativeFn,
+}
+
+impl LoxNative {
+    pub fn new(name: &str, arity: u8, function: NativeFn) -> Self {
+        Self { name: name.to_owned(), arity, function }
+    }
+}
+
+impl LoxClass {
+    pub fn new(name: String) -> Self {
+        Self { name, methods: RefCell::new(StringMap::default()) }
+    }
+}
+
+impl LoxInstance {
+    pub fn new(class: Rc<LoxClass>) -> Self {
+        Self { class, fields: RefCell::new(StringMap::default()) }
+    }
+}
+
+impl LoxList {
+    pub fn new(items: Vec<LoxValue>) -> Self {
+        Self { items: RefCell::new(items) }
+    }
+}
+
+impl LoxBoundMethod {
+    pub fn new(receiver: LoxValue, method: Rc<LoxClosure>) -> Self {
+        Self { receiver, method }
+    }
+}
+
+impl PartialEq for LoxObject {
+    fn eq(&self, other: &Self) -> bool {
+        match (self, other) {
+            // Interned strings compare their pointers first, see `LoxString`
+            (Self::String(a), Self::String(b)) => a == b,
+            // Functions are only equal to themselves
+            (Self::Function(a), Self::Function(b)) => Rc::ptr_eq(a, b),
+            (Self::Closure(a), Self::Closure(b)) => Rc::ptr_eq(a, b),
+            (Self::Class(a), Self::Class(b)) => Rc::ptr_eq(a, b),
+            (Self::Instance(a), Self::Instance(b)) => Rc::ptr_eq(a, b),
+            (Self::BoundMethod(a), Self::BoundMethod(b)) => Rc::ptr_eq(a, b),
+            (Self::Native(a), Self::Native(b)) => Rc::ptr_eq(a, b),
+            (Self::List(a), Self::List(b)) => Rc::ptr_eq(a, b),
+            _ => false,
+        }
+    }
+}
+
+impl Eq for LoxObject {}
@@ -0,0 +1,174 @@
+//! Interned strings.
+//!
+//! Every string created by a VM, whether it comes from a literal, a concatenation
+//! or a native function, goes through its string table. The table hands out a single
+//! shared handle per distinct string, so copying a string only bumps a reference count
+//! and comparing two strings usually comes down to comparing two pointers. The hash
+//! is computed once when the string is created, which makes strings cheap keys for
+//! globals, fields and methods.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::hash::{BuildHasherDefault, Hash, Hasher};
+use std::ops::Deref;
+use std::rc::{Rc, Weak};
+
+/// Number of entries in the string table before it's first checked for dead strings
+const INITIAL_PRUNE_THRESHOLD: usize = 1024;
+/// After pruning, the next one happens once the table grows by this factor
+const PRUNE_GROWTH_FACTOR: usize = 2;
+
+const FNV_OFFSET_BASIS: u64 = 0xcbf29ce484222325;
+const FNV_PRIME: u64 = 0x100000001b3;
+
+/// An immutable string along with its precomputed hash
+pub struct LoxString {
+    chars: Box<str>,
+    hash: u64,
+}
+
+/// Hash map keyed by strings, which reuses the hash stored in them
+pub type StringMap<V> = HashMap<Rc<LoxString>, V, BuildHasherDefault<StringHasher>>;
+
+/// Hasher that passes through the precomputed hash of a string
+#[derive(Default)]
+pub struct StringHasher(u64);
+
+/// Set of the strings that are alive, which makes sure there's only
+/// one copy of each of them. Strings are freed once nothing else uses
+/// them, so the table only keeps weak handles to them.
+pub struct StringTable {
+    // Strings by hash, there's usually only one per hash
+    strings: HashMap<u64, Vec<Weak<LoxString>>, BuildHasherDefault<StringHasher>>,
+    count: usize,
+    next_prune: usize,
+}
+
+impl LoxString {
+    pub fn new(chars: impl Into<Box<str>>) -> Self {
+        let chars = chars.into();
+        let hash = hash_str(&chars);
+        Self { chars, hash }
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.chars
+    }
+
+    pub fn hash_code(&self) -> u64 {
+        self.hash
+    }
+}
+
+impl StringTable {
+    pub fn new() -> Self {
+        Self { strings: HashMap::default(), count: 0, next_prune: INITIAL_PRUNE_THRESHOLD }
+    }
+
+    /** Returns the handle to the given string, creating it if it doesn't exist yet */
+    pub fn intern(&mut self, chars: &str) -> Rc<LoxString> {
+        match self.find(chars) {
+            Some(string) => string,
+            None => self.insert(Rc::new(LoxString::new(chars))),
+        }
+    }
+
+    /** Returns the handle to a string created outside of the table, such as
+    by an embedder, which becomes the interned one if there's none yet */
+    pub fn intern_rc(&mut self, string: Rc<LoxString>) -> Rc<LoxString> {
+        match self.lookup(&string.chars, string.hash) {
+            Some(interned) => interned,
+            None => self.insert(string),
+        }
+    }
+
+    /** Returns the handle to the given string, only if it already exists */
+    pub fn find(&self, chars: &str) -> Option<Rc<LoxString>> {
+        self.lookup(chars, hash_str(chars))
+    }
+
+    fn lookup(&self, chars: &str, hash: u64) -> Option<Rc<LoxString>> {
+        self.strings.get(&hash)?
+            .iter()
+            .filter_map(Weak::upgrade)
+            .find(|string| &*string.chars == chars)
+    }
+
+    fn insert(&mut self, string: Rc<LoxString>) -> Rc<LoxString> {
+        if self.count >= self.next_prune {
+            self.prune();
+        }
+
+        self.strings.entry(string.hash).or_default().push(Rc::downgrade(&string));
+        self.count += 1;
+        string
+    }
+
+    /** Forgets the strings that have been freed */
+    fn prune(&mut self) {
+        self.strings.retain(|_, strings| {
+            strings.retain(|string| string.strong_count() > 0);
+            !strings.is_empty()
+        });
+
+        self.count = self.strings.values().map(Vec::len).sum();
+        self.next_prune = (self.count * PRUNE_GROWTH_FACTOR).max(INITIAL_PRUNE_THRESHOLD);
+    }
+}
+
+impl Default for StringTable {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/** FNV-1a hash of the bytes of a string */
+fn hash_str(chars: &str) -> u64 {
+    chars.bytes().fold(FNV_OFFSET_BASIS, |hash, byte| (hash ^ byte as u64).wrapping_mul(FNV_PRIME))
+}
+
+impl PartialEq for LoxString {
+    fn eq(&self, other: &Self) -> bool {
+        // Interned strings are equal only if they're the same string. Strings
+        // that weren't interned yet need their contents compared, but only
+        // when their hashes match, which is rare for different strings.
+        std::ptr::eq(self, other) || (self.hash == other.hash && self.chars == other.chars)
+    }
+}
+
+impl Eq for LoxString {}
+
+impl Hash for LoxString {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        state.write_u64(self.hash);
+    }
+}
+
+impl Hasher for StringHasher {
+    fn finish(&self) -> u64 {
+        self.0
+    }
+
+    fn write(&mut self, bytes: &[u8]) {
+        // Only used by keys other than u64, which the maps above never have
+        self.0 = bytes.iter().fold(self.0 ^ FNV_OFFSET_BASIS, |hash, byte| (hash ^ *byte as u64).wrapping_mul(FNV_PRIME));
+    }
+
+    fn write_u64(&mut self, n: u64) {
+        self.0 = n;
+    }
+}
+
+impl Deref for LoxString {
+    type Target = str;
+
+    fn deref(&self) -> &str {
+        &self.chars
+    }
+}
+
+impl fmt::Display for LoxString {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.chars)
+    }
+}
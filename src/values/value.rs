@@ -1,5 +1,6 @@
-use std::ops::{Add, Sub, Mul, Div, Neg, Not, BitAnd, BitOr};
-use crate::values::LoxObject;
+use std::ops::{Add, Sub, Mul, Div, Rem, Neg, Not};
+use std::rc::Rc;
+use crate::values::{LoxList, LoxObject, LoxString};
 use LoxValue::*;
 
 #[derive(Clone, PartialEq, Default)]
@@ -23,6 +24,10 @@ impl LoxValue {
         matches!(self, Null | Bool(false))
     }
 
+    pub fn as_number(&self) -> Option<f64> {
+        if let Number(n) = self { Some(*n) } else { None }
+    }
+
     pub fn less(&self, rhs: &Self) -> Option<Self> {
         if let (Number(a), Number(b)) = (rhs, self) {
             Some(Bool(a < b))
@@ -42,6 +47,36 @@ impl LoxValue {
 
 impl Eq for LoxValue {}
 
+impl From<f64> for LoxValue {
+    fn from(n: f64) -> Self {
+        Number(n)
+    }
+}
+
+impl From<bool> for LoxValue {
+    fn from(b: bool) -> Self {
+        Bool(b)
+    }
+}
+
+impl From<&str> for LoxValue {
+    fn from(s: &str) -> Self {
+        Object(LoxObject::String(Rc::new(LoxString::new(s))))
+    }
+}
+
+impl From<String> for LoxValue {
+    fn from(s: String) -> Self {
+        Object(LoxObject::String(Rc::new(LoxString::new(s))))
+    }
+}
+
+impl From<Vec<LoxValue>> for LoxValue {
+    fn from(items: Vec<LoxValue>) -> Self {
+        Object(LoxObject::List(Rc::new(LoxList::new(items))))
+    }
+}
+
 impl Add<Self> for LoxValue {
     type Output = Option<Self>;
 
@@ -49,7 +84,7 @@ impl Add<Self> for LoxValue {
         match (rhs, self) {
             (Number(a), Number(b)) => Some(Number(a + b)),
             (Object(LoxObject::String(a)), Object(LoxObject::String(b))) => {
-                Some(Object(LoxObject::String(a + &b)))
+                Some(LoxValue::from(format!("{a}{b}")))
             },
             _ => None,
         }
@@ -92,48 +127,40 @@ impl Div<Self> for LoxValue {
     }
 }
 
-impl Neg for LoxValue {
-    type Output = Option<Self>;
-
-    fn neg(self) -> Self::Output {
-        if let Number(a) = self {
-            Some(Number(-a))
-        } else {
-            None
-        }
-    }
-}
-
-impl Not for LoxValue {
+/** Modulo follows the semantics of Rust's `%` for floats: the result is the remainder
+of the truncated division, so it takes the sign of the dividend (`-7 % 3 == -1`,
+`7 % -3 == 1`), works with fractional operands (`5.5 % 2 == 1.5`), and is NaN
+if the divisor is zero. */
+impl Rem<Self> for LoxValue {
     type Output = Option<Self>;
 
-    fn not(self) -> Self::Output {
-        if let Bool(b) = self {
-            Some(Bool(!b))
+    fn rem(self, rhs: Self) -> Self::Output {
+        if let (Number(a), Number(b)) = (rhs, self) {
+            Some(Number(a % b))
         } else {
             None
         }
     }
 }
 
-impl BitAnd for LoxValue {
+impl Neg for LoxValue {
     type Output = Option<Self>;
 
-    fn bitand(self, rhs: Self) -> Self::Output {
-        if let (Bool(a), Bool(b)) = (self, rhs) {
-            Some(Bool(a && b))
+    fn neg(self) -> Self::Output {
+        if let Number(a) = self {
+            Some(Number(-a))
         } else {
             None
         }
     }
 }
 
-impl BitOr for LoxValue {
+impl Not for LoxValue {
     type Output = Option<Self>;
 
-    fn bitor(self, rhs: Self) -> Self::Output {
-        if let (Bool(a), Bool(b)) = (self, rhs) {
-            Some(Bool(a || b))
+    fn not(self) -> Self::Output {
+        if let Bool(b) = self {
+            Some(Bool(!b))
         } else {
             None
         }
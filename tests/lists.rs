@@ -0,0 +1,71 @@
+//! Lists: literals, indexing, index assignment and the `len` native.
+
+use rlox::{LoxError, VM};
+
+/** Runs the code and returns the value of its last expression as it would be printed */
+fn eval(code: &str) -> String {
+    let mut vm = VM::new();
+    vm.eval(code).unwrap().to_string()
+}
+
+/** Runs the code and returns the message of the runtime error it raises */
+fn runtime_error(code: &str) -> String {
+    match VM::new().eval(code) {
+        Err(LoxError::Runtime(err)) => err.message,
+        Err(err) => panic!("Expected a runtime error, got {err}"),
+        Ok(value) => panic!("Expected a runtime error, got {value}"),
+    }
+}
+
+#[test]
+fn literals_and_indexing() {
+    assert_eq!(eval("[]"), "[]");
+    assert_eq!(eval("[1, \"two\", [3], null]"), "[1, \"two\", [3], null]");
+    assert_eq!(eval("let list = [1, 2, 3]; list[0] + list[2]"), "4");
+    assert_eq!(eval("[[1, 2], [3, 4]][1][0]"), "3");
+}
+
+#[test]
+fn index_assignment() {
+    assert_eq!(eval("let list = [1, 2, 3]; list[1] = 5; list"), "[1, 5, 3]");
+    assert_eq!(eval("let list = [1]; list[0] = 2"), "2");
+    assert_eq!(eval("let a = [0]; let b = [0]; a[0] = b[0] = 7; a[0] + b[0]"), "14");
+}
+
+#[test]
+fn compound_index_assignment() {
+    assert_eq!(eval("let list = [1, 2]; list[1] += 3; list[1] *= 2; list"), "[1, 10]");
+    assert_eq!(eval("let list = [\"a\"]; list[0] += \"b\""), "ab");
+
+    // The list and the index are only evaluated once
+    assert_eq!(eval("
+        let calls = 0;
+        let list = [1, 2];
+        fn index() { calls += 1; return 1; }
+        list[index()] -= 5;
+        [list, calls]
+    "), "[[1, -3], 1]");
+}
+
+#[test]
+fn lists_are_shared() {
+    assert_eq!(eval("let a = [1]; let b = a; b[0] = 2; a"), "[2]");
+    assert_eq!(eval("let a = [1]; a[0] = a; a"), "[[...]]");
+}
+
+#[test]
+fn len() {
+    assert_eq!(eval("len([])"), "0");
+    assert_eq!(eval("len([1, [2, 3]])"), "2");
+    assert_eq!(eval("len(\"日本\")"), "2");
+    assert_eq!(runtime_error("len(1)"), "Only lists and strings have a length.");
+}
+
+#[test]
+fn bad_indices() {
+    assert_eq!(runtime_error("1[0]"), "Only lists can be indexed.");
+    assert_eq!(runtime_error("[1][0.5]"), "List index must be an integer.");
+    assert_eq!(runtime_error("[1][\"0\"]"), "List index must be an integer.");
+    assert_eq!(runtime_error("[1][1]"), "List index 1 out of range for a list of length 1.");
+    assert_eq!(runtime_error("[1][-1] = 2"), "List index -1 out of range for a list of length 1.");
+}
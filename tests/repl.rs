@@ -0,0 +1,44 @@
+//! Runs the REPL as a separate process, feeding it input through its standard input.
+
+use std::io::Write;
+use std::process::{Command, Stdio};
+
+/** Runs the REPL with the given input, returning its exit status, output and errors */
+fn run_repl(input: &str) -> (bool, String, String) {
+    let mut child = Command::new(env!("CARGO_BIN_EXE_rlox"))
+        .arg("repl")
+        .env_remove("HOME")
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+
+    child.stdin.take().unwrap().write_all(input.as_bytes()).unwrap();
+    let output = child.wait_with_output().unwrap();
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    (output.status.success(), stdout, stderr)
+}
+
+#[test]
+fn bad_input_doesnt_end_the_session() {
+    let input = "\
+// café
+let = 1;
+é
+\"é\" + 1;
+undefined();
+let x = \"日本\";
+x
+1 + 1
+";
+    let (success, stdout, stderr) = run_repl(input);
+
+    assert!(success, "{stderr}");
+    assert!(stderr.contains("Expected variable name."), "{stderr}");
+    assert!(stderr.contains("Unexpected character."), "{stderr}");
+    assert!(stderr.contains("Values have incompatible types."), "{stderr}");
+    assert!(stderr.contains("Undefined variable 'undefined'."), "{stderr}");
+    assert!(stdout.ends_with("\"日本\"\n2\n"), "{stdout}");
+}